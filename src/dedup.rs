@@ -0,0 +1,165 @@
+use cloudevents::{AttributesReader, Event};
+use lru::LruCache;
+use serde::Deserialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Don't track processed events.
+    #[default]
+    None,
+    /// Record processed events in a PostgreSQL table, in the same transaction as the insertion.
+    Table,
+    /// Record processed events in an in-memory LRU cache.
+    Memory,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub mode: Mode,
+    #[serde(default = "default_table")]
+    pub table: String,
+    #[serde(default = "default_capacity")]
+    pub capacity: usize,
+    #[serde(default = "default_ttl", with = "humantime_serde")]
+    pub ttl: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mode: Mode::default(),
+            table: default_table(),
+            capacity: default_capacity(),
+            ttl: default_ttl(),
+        }
+    }
+}
+
+#[inline]
+fn default_table() -> String {
+    "processed_events".into()
+}
+
+#[inline]
+fn default_capacity() -> usize {
+    10_000
+}
+
+#[inline]
+fn default_ttl() -> Duration {
+    Duration::from_secs(60 * 60)
+}
+
+/// The identity of a cloud event, as defined by the spec: `source` + `id`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct EventKey {
+    pub source: String,
+    pub id: String,
+}
+
+impl From<&Event> for EventKey {
+    fn from(event: &Event) -> Self {
+        Self {
+            source: event.source().to_string(),
+            id: event.id().to_string(),
+        }
+    }
+}
+
+pub enum Deduplicator {
+    None,
+    Table(String),
+    Memory {
+        cache: Mutex<LruCache<EventKey, Instant>>,
+        ttl: Duration,
+    },
+}
+
+impl Deduplicator {
+    pub fn new(config: Config) -> Self {
+        match config.mode {
+            Mode::None => Self::None,
+            Mode::Table => Self::Table(config.table),
+            Mode::Memory => Self::Memory {
+                cache: Mutex::new(LruCache::new(config.capacity)),
+                ttl: config.ttl,
+            },
+        }
+    }
+
+    /// The table to record processed events in, if the deduplication is backed by the database.
+    pub fn table(&self) -> Option<&str> {
+        match self {
+            Self::Table(table) => Some(table),
+            _ => None,
+        }
+    }
+
+    /// Check if the event was already processed.
+    ///
+    /// This only covers the in-memory mode, the table mode is checked when writing.
+    pub fn is_duplicate(&self, key: &EventKey) -> bool {
+        match self {
+            Self::Memory { cache, ttl } => {
+                let mut cache = cache.lock().unwrap();
+                match cache.get(key) {
+                    Some(processed) if processed.elapsed() < *ttl => true,
+                    Some(_) => {
+                        cache.pop(key);
+                        false
+                    }
+                    None => false,
+                }
+            }
+            _ => false,
+        }
+    }
+
+    /// Record the event as processed.
+    pub fn mark(&self, key: EventKey) {
+        if let Self::Memory { cache, .. } = self {
+            cache.lock().unwrap().put(key, Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn key(id: &str) -> EventKey {
+        EventKey {
+            source: "drogue".into(),
+            id: id.into(),
+        }
+    }
+
+    #[test]
+    fn test_memory() {
+        let dedup = Deduplicator::new(Config {
+            mode: Mode::Memory,
+            ..Default::default()
+        });
+
+        assert!(!dedup.is_duplicate(&key("1")));
+        dedup.mark(key("1"));
+        assert!(dedup.is_duplicate(&key("1")));
+        assert!(!dedup.is_duplicate(&key("2")));
+    }
+
+    #[test]
+    fn test_memory_expired() {
+        let dedup = Deduplicator::new(Config {
+            mode: Mode::Memory,
+            ttl: Duration::from_secs(0),
+            ..Default::default()
+        });
+
+        dedup.mark(key("1"));
+        assert!(!dedup.is_duplicate(&key("1")));
+    }
+}
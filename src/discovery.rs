@@ -0,0 +1,277 @@
+use crate::{
+    error::ServiceError,
+    filter::glob,
+    selector::Selector,
+    writer::{column_name, PostgresWriter, Type},
+};
+use regex::Regex;
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    /// The selector of the object to discover fields from.
+    pub path: Option<String>,
+    /// The separator for the names of nested values.
+    #[serde(default = "default_separator")]
+    pub separator: String,
+    /// Comma separated patterns of names to include.
+    pub include: Option<String>,
+    /// Comma separated patterns of names to exclude.
+    pub exclude: Option<String>,
+    /// Only write fields for which the target table has a column.
+    #[serde(default)]
+    pub existing_columns_only: bool,
+    /// The duration to cache the columns of the target table.
+    #[serde(default = "default_columns_ttl", with = "humantime_serde")]
+    pub columns_ttl: Duration,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            path: None,
+            separator: default_separator(),
+            include: None,
+            exclude: None,
+            existing_columns_only: false,
+            columns_ttl: default_columns_ttl(),
+        }
+    }
+}
+
+fn default_separator() -> String {
+    "_".into()
+}
+
+#[inline]
+fn default_columns_ttl() -> Duration {
+    Duration::from_secs(60)
+}
+
+/// Discovers fields from all scalar values of an object.
+pub struct Discovery {
+    path: String,
+    selector: Selector,
+    separator: String,
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+    existing_columns_only: bool,
+    columns_ttl: Duration,
+    columns: Mutex<Option<(Instant, Arc<HashSet<String>>)>>,
+}
+
+fn patterns(patterns: Option<String>) -> anyhow::Result<Vec<Regex>> {
+    patterns
+        .iter()
+        .flat_map(|patterns| patterns.split(','))
+        .map(str::trim)
+        .filter(|pattern| !pattern.is_empty())
+        .map(|pattern| glob(pattern).map_err(Into::into))
+        .collect()
+}
+
+impl Discovery {
+    /// Create a new discovery, or `None` if no path is configured.
+    pub fn new(config: Config) -> anyhow::Result<Option<Self>> {
+        let path = match config.path {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+
+        Ok(Some(Self {
+            selector: Selector::try_from(path.as_str())?,
+            path,
+            separator: config.separator,
+            include: patterns(config.include)?,
+            exclude: patterns(config.exclude)?,
+            existing_columns_only: config.existing_columns_only,
+            columns_ttl: config.columns_ttl,
+            columns: Default::default(),
+        }))
+    }
+
+    /// Discover fields from the payload.
+    ///
+    /// Values are skipped if their name is matched by `skip`, or they are not accepted by the
+    /// include and exclude patterns.
+    pub async fn discover<F>(
+        &self,
+        json: &Value,
+        writer: &PostgresWriter,
+        skip: F,
+    ) -> Result<Vec<(String, Type)>, ServiceError>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let sel = self.selector.select(json)?;
+        let object = match sel.as_slice() {
+            [] => return Ok(vec![]),
+            [v] if v.is_object() => v,
+            [_] => {
+                return Err(ServiceError::Selector(format!(
+                    "Discovery path must select an object: {}",
+                    self.path
+                )))
+            }
+            [..] => {
+                return Err(ServiceError::Selector(format!(
+                    "Selector found more than one value: {}",
+                    sel.len()
+                )))
+            }
+        };
+
+        let mut result = self.fields(object, skip)?;
+
+        if self.existing_columns_only {
+            let columns = self.columns(writer).await?;
+            result.retain(|(name, _)| columns.contains(name));
+        }
+
+        Ok(result)
+    }
+
+    /// Flatten the object into fields, which are accepted.
+    ///
+    /// Different names may result in the same column name (e.g. `Temp` and `temp`), in which case
+    /// the payload is rejected, as it is unclear which value should be written.
+    fn fields<F>(&self, object: &Value, skip: F) -> Result<Vec<(String, Type)>, ServiceError>
+    where
+        F: Fn(&str) -> bool,
+    {
+        let mut result = Vec::new();
+        self.flatten(None, object, &mut result);
+
+        result.retain(|(name, _)| !skip(name.as_str()) && self.accepts(name));
+
+        let mut names = HashSet::with_capacity(result.len());
+        for (name, _) in &result {
+            if name.is_empty() {
+                return Err(ServiceError::PayloadParse(
+                    "Discovered value without a name".into(),
+                ));
+            }
+            if !names.insert(name.as_str()) {
+                return Err(ServiceError::PayloadParse(format!(
+                    "Discovered values collide in column: {}",
+                    name
+                )));
+            }
+        }
+
+        Ok(result)
+    }
+
+    fn accepts(&self, name: &str) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|p| p.is_match(name)))
+            && !self.exclude.iter().any(|p| p.is_match(name))
+    }
+
+    fn flatten(&self, prefix: Option<&str>, value: &Value, result: &mut Vec<(String, Type)>) {
+        let name = |key: &str| match prefix {
+            Some(prefix) => format!("{}{}{}", prefix, self.separator, key),
+            None => key.to_string(),
+        };
+
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map {
+                    self.flatten(Some(&name(key)), value, result);
+                }
+            }
+            Value::Array(values) => {
+                for (i, value) in values.iter().enumerate() {
+                    self.flatten(Some(&name(&i.to_string())), value, result);
+                }
+            }
+            // skip null values, like absent ones
+            Value::Null => {}
+            Value::Bool(b) => {
+                result.push((column_name(prefix.unwrap_or_default()), Type::Boolean(*b)))
+            }
+            Value::Number(n) => {
+                if let Some(f) = n.as_f64() {
+                    result.push((column_name(prefix.unwrap_or_default()), Type::Float(f)));
+                }
+            }
+            Value::String(s) => result.push((
+                column_name(prefix.unwrap_or_default()),
+                Type::String(s.clone()),
+            )),
+        }
+    }
+
+    /// Get the columns of the target table, cached for the configured duration.
+    async fn columns(&self, writer: &PostgresWriter) -> Result<Arc<HashSet<String>>, ServiceError> {
+        if let Some((timestamp, columns)) = &*self.columns.lock().unwrap() {
+            if timestamp.elapsed() < self.columns_ttl {
+                return Ok(columns.clone());
+            }
+        }
+
+        let columns = Arc::new(writer.columns().await?);
+        *self.columns.lock().unwrap() = Some((Instant::now(), columns.clone()));
+
+        Ok(columns)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_flatten() {
+        let discovery = Discovery::new(Config {
+            path: Some("$.env".into()),
+            exclude: Some("*_raw".into()),
+            ..Default::default()
+        })
+        .unwrap()
+        .unwrap();
+
+        let json = json!({"Temp": 21.5, "wind": {"speed": 3, "dir": "NW"}, "levels": [1, null], "temp_raw": 2150});
+        let result = discovery.fields(&json, |_| false).unwrap();
+
+        let mut names: Vec<&str> = result.iter().map(|(name, _)| name.as_str()).collect();
+        names.sort_unstable();
+        assert_eq!(names, vec!["levels_0", "temp", "wind_dir", "wind_speed"]);
+    }
+
+    #[test]
+    fn test_collisions() {
+        let discovery = Discovery::new(Config {
+            path: Some("$.env".into()),
+            exclude: Some("skipped".into()),
+            ..Default::default()
+        })
+        .unwrap()
+        .unwrap();
+
+        assert!(discovery
+            .fields(&json!({"Temp": 1, "temp": 2}), |_| false)
+            .is_err());
+        assert!(discovery
+            .fields(&json!({"wind": {"speed": 1}, "wind_speed": 2}), |_| false)
+            .is_err());
+        assert!(discovery
+            .fields(&json!({"wind-speed": 1, "wind.speed": 2}), |_| false)
+            .is_err());
+        assert!(discovery.fields(&json!({"": 1}), |_| false).is_err());
+
+        // only accepted values are considered
+        assert!(discovery
+            .fields(&json!({"Skipped": 1, "skipped": 2}), |_| false)
+            .unwrap()
+            .is_empty());
+        assert!(discovery
+            .fields(&json!({"": 1, "temp": 2}), |name| name.is_empty())
+            .is_ok());
+    }
+}
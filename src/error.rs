@@ -1,5 +1,6 @@
 use actix_web::{HttpResponse, ResponseError};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -12,6 +13,22 @@ pub enum ServiceError {
     Conversion(String),
     #[error("Error connecting target: {0}")]
     Target(String),
+    #[error("Service unavailable: {0}")]
+    Unavailable(String),
+    #[error("Too many requests: {0}")]
+    Overloaded(String),
+    #[error("Failed processing {} field(s)", .0.len())]
+    Fields(Vec<FieldError>),
+}
+
+impl ServiceError {
+    /// Check if the error is transient, and processing the event again may succeed.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            Self::Target(_) | Self::Unavailable(_) | Self::Overloaded(_)
+        )
+    }
 }
 
 impl ResponseError for ServiceError {
@@ -21,20 +38,43 @@ impl ResponseError for ServiceError {
             ServiceError::Selector { .. } => HttpResponse::NotAcceptable().json(ErrorResponse {
                 error: "SelectorError".into(),
                 message,
+                fields: vec![],
             }),
             ServiceError::PayloadParse { .. } => {
                 HttpResponse::NotAcceptable().json(ErrorResponse {
                     error: "PayloadError".into(),
                     message,
+                    fields: vec![],
                 })
             }
             ServiceError::Conversion { .. } => HttpResponse::NotAcceptable().json(ErrorResponse {
                 error: "ConversionError".into(),
                 message,
+                fields: vec![],
             }),
             ServiceError::Target { .. } => HttpResponse::BadGateway().json(ErrorResponse {
                 error: "TargetError".into(),
                 message,
+                fields: vec![],
+            }),
+            ServiceError::Unavailable { .. } => {
+                HttpResponse::ServiceUnavailable().json(ErrorResponse {
+                    error: "Unavailable".into(),
+                    message,
+                    fields: vec![],
+                })
+            }
+            ServiceError::Overloaded { .. } => {
+                HttpResponse::TooManyRequests().json(ErrorResponse {
+                    error: "Overloaded".into(),
+                    message,
+                    fields: vec![],
+                })
+            }
+            ServiceError::Fields(fields) => HttpResponse::NotAcceptable().json(ErrorResponse {
+                error: "FieldsError".into(),
+                message,
+                fields: fields.clone(),
             }),
         }
     }
@@ -44,4 +84,18 @@ impl ResponseError for ServiceError {
 pub struct ErrorResponse {
     pub error: String,
     pub message: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fields: Vec<FieldError>,
+}
+
+/// Diagnostics for a single field (or tag) which failed processing.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldError {
+    pub field: String,
+    pub path: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<Value>,
+    pub expected_type: String,
+    pub reason: String,
 }
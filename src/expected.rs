@@ -1,8 +1,10 @@
 use crate::error::ServiceError;
 use crate::{extract::Path, writer::Type};
+use serde::Deserialize;
 use serde_json::Value;
 use std::convert::{TryFrom, TryInto};
 use std::env::VarError;
+use std::fmt::{Display, Formatter};
 use std::str::FromStr;
 
 #[derive(Debug, Clone)]
@@ -32,7 +34,7 @@ impl ExpectedType {
         let result = ext(value);
 
         // or else a missing value error
-        let result = result.ok_or_else(|| ServiceError::PayloadParse(format!("Missing value")));
+        let result = result.ok_or_else(|| ServiceError::PayloadParse("Missing value".to_string()));
 
         // unless conversion is disabled ...
         let result = if !disable_try_parse {
@@ -105,6 +107,19 @@ impl ExpectedType {
     }
 }
 
+impl Display for ExpectedType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ExpectedType::Boolean => f.write_str("bool"),
+            ExpectedType::Float => f.write_str("float"),
+            ExpectedType::SignedInteger => f.write_str("int"),
+            ExpectedType::UnsignedInteger => f.write_str("uint"),
+            ExpectedType::Text => f.write_str("string"),
+            ExpectedType::None => f.write_str("none"),
+        }
+    }
+}
+
 impl TryFrom<String> for ExpectedType {
     type Error = anyhow::Error;
 
@@ -134,3 +149,71 @@ impl TryFrom<Result<String, VarError>> for ExpectedType {
             .map_or_else(|| Ok(ExpectedType::None), TryInto::try_into)
     }
 }
+
+/// What to do when a field (or tag) fails to be processed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ErrorPolicy {
+    /// Fail processing the event.
+    #[default]
+    Fail,
+    /// Skip the field, writing the row without it.
+    SkipField,
+    /// Write `NULL` for the field.
+    Null,
+}
+
+impl Display for ErrorPolicy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ErrorPolicy::Fail => f.write_str("fail"),
+            ErrorPolicy::SkipField => f.write_str("skip-field"),
+            ErrorPolicy::Null => f.write_str("null"),
+        }
+    }
+}
+
+impl TryFrom<String> for ErrorPolicy {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.to_lowercase().as_str() {
+            "fail" => Ok(ErrorPolicy::Fail),
+            "skip-field" | "skip" => Ok(ErrorPolicy::SkipField),
+            "null" => Ok(ErrorPolicy::Null),
+            _ => anyhow::bail!("Unknown error policy: {}", value),
+        }
+    }
+}
+
+impl ErrorPolicy {
+    /// Evaluate the policy from an environment variable, falling back to the default.
+    pub fn from_env(value: Result<String, VarError>, default: ErrorPolicy) -> anyhow::Result<Self> {
+        match value {
+            Ok(value) => value.try_into(),
+            Err(VarError::NotPresent) => Ok(default),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_policy_from_env() {
+        let policy = |value: &str| ErrorPolicy::from_env(Ok(value.into()), ErrorPolicy::Fail);
+
+        assert_eq!(policy("skip").unwrap(), ErrorPolicy::SkipField);
+        assert_eq!(policy("Skip-Field").unwrap(), ErrorPolicy::SkipField);
+        assert_eq!(policy("null").unwrap(), ErrorPolicy::Null);
+        assert_eq!(policy("fail").unwrap(), ErrorPolicy::Fail);
+        assert!(policy("ignore").is_err());
+
+        assert_eq!(
+            ErrorPolicy::from_env(Err(VarError::NotPresent), ErrorPolicy::Null).unwrap(),
+            ErrorPolicy::Null
+        );
+    }
+}
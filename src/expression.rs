@@ -0,0 +1,708 @@
+use crate::{error::ServiceError, writer::Type};
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+use std::convert::{TryFrom, TryInto};
+use std::fmt::{Display, Formatter};
+
+/// An expression for computing a value, from other extracted values and cloud event attributes.
+///
+/// The language is intentionally small: literals, references, arithmetic, comparison and logical
+/// operators, a conditional (`cond ? a : b`) and a fixed set of pure functions. There are no
+/// loops, assignments or any way to access anything outside of the evaluated event.
+#[derive(Debug, Clone)]
+pub enum Expression {
+    Literal(Type),
+    /// A reference to an extracted field or tag.
+    Reference(String),
+    /// A reference to a cloud event attribute (`ce.<name>`).
+    Attribute(String),
+    Unary(UnaryOp, Box<Expression>),
+    Binary(BinaryOp, Box<Expression>, Box<Expression>),
+    Conditional(Box<Expression>, Box<Expression>, Box<Expression>),
+    Call(Function, Vec<Expression>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Not,
+    Negate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    Abs,
+    Min,
+    Max,
+    Round,
+    Floor,
+    Ceil,
+    Sqrt,
+    Pow,
+    Concat,
+    Lower,
+    Upper,
+    Coalesce,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "abs" => Self::Abs,
+            "min" => Self::Min,
+            "max" => Self::Max,
+            "round" => Self::Round,
+            "floor" => Self::Floor,
+            "ceil" => Self::Ceil,
+            "sqrt" => Self::Sqrt,
+            "pow" => Self::Pow,
+            "concat" => Self::Concat,
+            "lower" => Self::Lower,
+            "upper" => Self::Upper,
+            "coalesce" => Self::Coalesce,
+            _ => return None,
+        })
+    }
+
+    /// The minimum and maximum number of arguments.
+    fn arity(&self) -> (usize, usize) {
+        match self {
+            Self::Abs | Self::Floor | Self::Ceil | Self::Sqrt => (1, 1),
+            Self::Lower | Self::Upper => (1, 1),
+            Self::Round => (1, 2),
+            Self::Pow => (2, 2),
+            Self::Min | Self::Max | Self::Concat | Self::Coalesce => (1, usize::MAX),
+        }
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&format!("{:?}", self).to_lowercase())
+    }
+}
+
+/// The values an expression is evaluated against.
+pub struct Context<'a> {
+    /// Extracted fields and tags.
+    pub values: &'a HashMap<&'a str, Type>,
+    /// The JSON representation of the cloud event.
+    pub event: &'a Value,
+}
+
+impl Expression {
+    /// Names of all fields and tags referenced by the expression.
+    pub fn references(&self) -> HashSet<&str> {
+        let mut result = HashSet::new();
+        self.collect_references(&mut result);
+        result
+    }
+
+    fn collect_references<'a>(&'a self, result: &mut HashSet<&'a str>) {
+        match self {
+            Self::Literal(_) | Self::Attribute(_) => {}
+            Self::Reference(name) => {
+                result.insert(name);
+            }
+            Self::Unary(_, e) => e.collect_references(result),
+            Self::Binary(_, l, r) => {
+                l.collect_references(result);
+                r.collect_references(result);
+            }
+            Self::Conditional(c, a, b) => {
+                c.collect_references(result);
+                a.collect_references(result);
+                b.collect_references(result);
+            }
+            Self::Call(_, args) => args.iter().for_each(|e| e.collect_references(result)),
+        }
+    }
+
+    pub fn evaluate(&self, context: &Context) -> Result<Type, ServiceError> {
+        match self {
+            Self::Literal(value) => Ok(value.clone()),
+            Self::Reference(name) => Ok(context
+                .values
+                .get(name.as_str())
+                .cloned()
+                .unwrap_or(Type::Null)),
+            Self::Attribute(name) => Ok(match context.event.get(name) {
+                Some(Value::String(s)) => Type::String(s.clone()),
+                Some(Value::Bool(b)) => Type::Boolean(*b),
+                Some(Value::Number(n)) => n
+                    .as_i64()
+                    .map(Type::SignedInteger)
+                    .or_else(|| n.as_f64().map(Type::Float))
+                    .unwrap_or(Type::Null),
+                _ => Type::Null,
+            }),
+            Self::Unary(op, e) => unary(*op, e.evaluate(context)?),
+            Self::Binary(BinaryOp::And, l, r) => Ok(Type::Boolean(
+                truthy(&l.evaluate(context)?)? && truthy(&r.evaluate(context)?)?,
+            )),
+            Self::Binary(BinaryOp::Or, l, r) => Ok(Type::Boolean(
+                truthy(&l.evaluate(context)?)? || truthy(&r.evaluate(context)?)?,
+            )),
+            Self::Binary(op, l, r) => binary(*op, l.evaluate(context)?, r.evaluate(context)?),
+            Self::Conditional(c, a, b) => match truthy(&c.evaluate(context)?)? {
+                true => a.evaluate(context),
+                false => b.evaluate(context),
+            },
+            Self::Call(f, args) => {
+                let args = args
+                    .iter()
+                    .map(|e| e.evaluate(context))
+                    .collect::<Result<Vec<_>, _>>()?;
+                call(*f, args)
+            }
+        }
+    }
+}
+
+fn error<T>(message: String) -> Result<T, ServiceError> {
+    Err(ServiceError::Conversion(message))
+}
+
+fn truthy(value: &Type) -> Result<bool, ServiceError> {
+    match value {
+        Type::Boolean(b) => Ok(*b),
+        Type::Null => Ok(false),
+        _ => error(format!("Expected boolean value, found: {:?}", value)),
+    }
+}
+
+fn integer(value: &Type) -> Option<i64> {
+    match value {
+        Type::SignedInteger(v) => Some(*v),
+        Type::UnsignedInteger(v) => (*v).try_into().ok(),
+        _ => None,
+    }
+}
+
+fn number(value: &Type) -> Result<f64, ServiceError> {
+    match value {
+        Type::Float(v) => Ok(*v),
+        Type::SignedInteger(v) => Ok(*v as f64),
+        Type::UnsignedInteger(v) => Ok(*v as f64),
+        _ => error(format!("Expected numeric value, found: {:?}", value)),
+    }
+}
+
+fn string(value: &Type) -> String {
+    match value {
+        Type::Boolean(v) => v.to_string(),
+        Type::Float(v) => v.to_string(),
+        Type::SignedInteger(v) => v.to_string(),
+        Type::UnsignedInteger(v) => v.to_string(),
+        Type::String(v) => v.clone(),
+        Type::Null => String::new(),
+    }
+}
+
+fn equals(l: &Type, r: &Type) -> bool {
+    match (l, r) {
+        (Type::Null, Type::Null) => true,
+        (Type::Boolean(l), Type::Boolean(r)) => l == r,
+        (Type::String(l), Type::String(r)) => l == r,
+        (l, r) => match (number(l), number(r)) {
+            (Ok(l), Ok(r)) => l == r,
+            _ => false,
+        },
+    }
+}
+
+fn unary(op: UnaryOp, value: Type) -> Result<Type, ServiceError> {
+    match (op, value) {
+        (_, Type::Null) => Ok(Type::Null),
+        (UnaryOp::Not, value) => Ok(Type::Boolean(!truthy(&value)?)),
+        (UnaryOp::Negate, Type::SignedInteger(v)) => match v.checked_neg() {
+            Some(v) => Ok(Type::SignedInteger(v)),
+            None => error(format!("Integer overflow negating: {}", v)),
+        },
+        (UnaryOp::Negate, value) => Ok(Type::Float(-number(&value)?)),
+    }
+}
+
+fn binary(op: BinaryOp, l: Type, r: Type) -> Result<Type, ServiceError> {
+    match op {
+        BinaryOp::Equal => return Ok(Type::Boolean(equals(&l, &r))),
+        BinaryOp::NotEqual => return Ok(Type::Boolean(!equals(&l, &r))),
+        _ => {}
+    }
+
+    if matches!(l, Type::Null) || matches!(r, Type::Null) {
+        return Ok(Type::Null);
+    }
+
+    match (op, &l, &r) {
+        (BinaryOp::Add, Type::String(l), Type::String(r)) => {
+            return Ok(Type::String(format!("{}{}", l, r)))
+        }
+        (BinaryOp::Less, Type::String(l), Type::String(r)) => return Ok(Type::Boolean(l < r)),
+        (BinaryOp::LessOrEqual, Type::String(l), Type::String(r)) => {
+            return Ok(Type::Boolean(l <= r))
+        }
+        (BinaryOp::Greater, Type::String(l), Type::String(r)) => return Ok(Type::Boolean(l > r)),
+        (BinaryOp::GreaterOrEqual, Type::String(l), Type::String(r)) => {
+            return Ok(Type::Boolean(l >= r))
+        }
+        _ => {}
+    }
+
+    // integer arithmetic, as long as it doesn't overflow
+
+    if let (Some(a), Some(b)) = (integer(&l), integer(&r)) {
+        let result = match op {
+            BinaryOp::Add => a.checked_add(b),
+            BinaryOp::Subtract => a.checked_sub(b),
+            BinaryOp::Multiply => a.checked_mul(b),
+            BinaryOp::Remainder => a.checked_rem(b),
+            _ => None,
+        };
+        if let Some(result) = result {
+            return Ok(Type::SignedInteger(result));
+        }
+    }
+
+    let (a, b) = (number(&l)?, number(&r)?);
+    Ok(match op {
+        BinaryOp::Add => Type::Float(a + b),
+        BinaryOp::Subtract => Type::Float(a - b),
+        BinaryOp::Multiply => Type::Float(a * b),
+        BinaryOp::Divide if b == 0.0 => return error("Division by zero".into()),
+        BinaryOp::Divide => Type::Float(a / b),
+        BinaryOp::Remainder if b == 0.0 => return error("Division by zero".into()),
+        BinaryOp::Remainder => Type::Float(a % b),
+        BinaryOp::Less => Type::Boolean(a < b),
+        BinaryOp::LessOrEqual => Type::Boolean(a <= b),
+        BinaryOp::Greater => Type::Boolean(a > b),
+        BinaryOp::GreaterOrEqual => Type::Boolean(a >= b),
+        BinaryOp::Equal | BinaryOp::NotEqual | BinaryOp::And | BinaryOp::Or => unreachable!(),
+    })
+}
+
+fn call(f: Function, args: Vec<Type>) -> Result<Type, ServiceError> {
+    // all functions, except coalesce, propagate NULL
+    if f != Function::Coalesce && args.iter().any(|arg| matches!(arg, Type::Null)) {
+        return Ok(Type::Null);
+    }
+
+    Ok(match f {
+        Function::Abs => match integer(&args[0]) {
+            Some(v) => match v.checked_abs() {
+                Some(v) => Type::SignedInteger(v),
+                None => return error(format!("Integer overflow in abs: {}", v)),
+            },
+            None => Type::Float(number(&args[0])?.abs()),
+        },
+        Function::Min | Function::Max => {
+            let mut result = number(&args[0])?;
+            for arg in &args[1..] {
+                let arg = number(arg)?;
+                result = match f {
+                    Function::Min => result.min(arg),
+                    _ => result.max(arg),
+                };
+            }
+            Type::Float(result)
+        }
+        Function::Round => {
+            let digits = match args.get(1) {
+                Some(digits) => integer(digits)
+                    .ok_or_else(|| ServiceError::Conversion("Expected integer digits".into()))?,
+                None => 0,
+            };
+            let factor = 10f64.powi(digits as i32);
+            Type::Float((number(&args[0])? * factor).round() / factor)
+        }
+        Function::Floor => Type::Float(number(&args[0])?.floor()),
+        Function::Ceil => Type::Float(number(&args[0])?.ceil()),
+        Function::Sqrt => Type::Float(number(&args[0])?.sqrt()),
+        Function::Pow => Type::Float(number(&args[0])?.powf(number(&args[1])?)),
+        Function::Concat => Type::String(args.iter().map(string).collect()),
+        Function::Lower => Type::String(string(&args[0]).to_lowercase()),
+        Function::Upper => Type::String(string(&args[0]).to_uppercase()),
+        Function::Coalesce => args
+            .into_iter()
+            .find(|arg| !matches!(arg, Type::Null))
+            .unwrap_or(Type::Null),
+    })
+}
+
+// parsing
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Integer(i64),
+    String(String),
+    Identifier(String),
+    Operator(&'static str),
+    LeftParen,
+    RightParen,
+    Comma,
+    Question,
+    Colon,
+}
+
+const OPERATORS: &[&str] = &[
+    "==", "!=", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "<", ">", "!",
+];
+
+fn tokenize(input: &str) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = input.chars().collect();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token::LeftParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RightParen);
+                i += 1;
+            }
+            ',' => {
+                tokens.push(Token::Comma);
+                i += 1;
+            }
+            '?' => {
+                tokens.push(Token::Question);
+                i += 1;
+            }
+            ':' => {
+                tokens.push(Token::Colon);
+                i += 1;
+            }
+            '"' | '\'' => {
+                let mut s = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => anyhow::bail!("Unterminated string literal"),
+                        Some('\\') => {
+                            s.extend(chars.get(i + 1));
+                            i += 2;
+                        }
+                        Some(q) if *q == c => {
+                            i += 1;
+                            break;
+                        }
+                        Some(x) => {
+                            s.push(*x);
+                            i += 1;
+                        }
+                    }
+                }
+                tokens.push(Token::String(s));
+            }
+            c if c.is_ascii_digit() => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                    i += 1;
+                }
+                let s: String = chars[start..i].iter().collect();
+                tokens.push(match s.parse::<i64>() {
+                    Ok(v) => Token::Integer(v),
+                    Err(_) => Token::Number(
+                        s.parse()
+                            .map_err(|_| anyhow::anyhow!("Invalid number: {}", s))?,
+                    ),
+                });
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len()
+                    && (chars[i].is_alphanumeric() || chars[i] == '_' || chars[i] == '.')
+                {
+                    i += 1;
+                }
+                tokens.push(Token::Identifier(chars[start..i].iter().collect()));
+            }
+            _ => {
+                let rest: String = chars[i..].iter().take(2).collect();
+                let op = OPERATORS
+                    .iter()
+                    .find(|op| rest.starts_with(*op))
+                    .ok_or_else(|| anyhow::anyhow!("Unexpected character: {}", c))?;
+                tokens.push(Token::Operator(op));
+                i += op.len();
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> anyhow::Result<()> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            token => anyhow::bail!("Expected {:?}, found: {:?}", expected, token),
+        }
+    }
+
+    fn conditional(&mut self) -> anyhow::Result<Expression> {
+        let condition = self.binary(0)?;
+        if self.peek() == Some(&Token::Question) {
+            self.next();
+            let a = self.conditional()?;
+            self.expect(Token::Colon)?;
+            let b = self.conditional()?;
+            return Ok(Expression::Conditional(
+                Box::new(condition),
+                Box::new(a),
+                Box::new(b),
+            ));
+        }
+        Ok(condition)
+    }
+
+    /// Precedence climbing over the binary operators.
+    fn binary(&mut self, min_precedence: u8) -> anyhow::Result<Expression> {
+        let mut left = self.unary()?;
+
+        while let Some(Token::Operator(op)) = self.peek() {
+            let (op, precedence) = match *op {
+                "||" => (BinaryOp::Or, 0),
+                "&&" => (BinaryOp::And, 1),
+                "==" => (BinaryOp::Equal, 2),
+                "!=" => (BinaryOp::NotEqual, 2),
+                "<" => (BinaryOp::Less, 3),
+                "<=" => (BinaryOp::LessOrEqual, 3),
+                ">" => (BinaryOp::Greater, 3),
+                ">=" => (BinaryOp::GreaterOrEqual, 3),
+                "+" => (BinaryOp::Add, 4),
+                "-" => (BinaryOp::Subtract, 4),
+                "*" => (BinaryOp::Multiply, 5),
+                "/" => (BinaryOp::Divide, 5),
+                "%" => (BinaryOp::Remainder, 5),
+                op => anyhow::bail!("Unexpected operator: {}", op),
+            };
+            if precedence < min_precedence {
+                break;
+            }
+            self.next();
+            let right = self.binary(precedence + 1)?;
+            left = Expression::Binary(op, Box::new(left), Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> anyhow::Result<Expression> {
+        match self.peek() {
+            Some(Token::Operator("!")) => {
+                self.next();
+                Ok(Expression::Unary(UnaryOp::Not, Box::new(self.unary()?)))
+            }
+            Some(Token::Operator("-")) => {
+                self.next();
+                Ok(Expression::Unary(UnaryOp::Negate, Box::new(self.unary()?)))
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> anyhow::Result<Expression> {
+        match self.next() {
+            Some(Token::Integer(v)) => Ok(Expression::Literal(Type::SignedInteger(v))),
+            Some(Token::Number(v)) => Ok(Expression::Literal(Type::Float(v))),
+            Some(Token::String(s)) => Ok(Expression::Literal(Type::String(s))),
+            Some(Token::LeftParen) => {
+                let e = self.conditional()?;
+                self.expect(Token::RightParen)?;
+                Ok(e)
+            }
+            Some(Token::Identifier(name)) => {
+                if self.peek() == Some(&Token::LeftParen) {
+                    return self.call(&name);
+                }
+                Ok(match name.as_str() {
+                    "true" => Expression::Literal(Type::Boolean(true)),
+                    "false" => Expression::Literal(Type::Boolean(false)),
+                    "null" => Expression::Literal(Type::Null),
+                    name => match name.strip_prefix("ce.") {
+                        Some(attribute) if !attribute.is_empty() => {
+                            Expression::Attribute(attribute.to_string())
+                        }
+                        _ if name.contains('.') => anyhow::bail!("Invalid reference: {}", name),
+                        _ => Expression::Reference(name.to_lowercase()),
+                    },
+                })
+            }
+            token => anyhow::bail!("Unexpected token: {:?}", token),
+        }
+    }
+
+    fn call(&mut self, name: &str) -> anyhow::Result<Expression> {
+        let f = Function::from_name(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown function: {}", name))?;
+
+        self.expect(Token::LeftParen)?;
+        let mut args = Vec::new();
+        if self.peek() != Some(&Token::RightParen) {
+            loop {
+                args.push(self.conditional()?);
+                if self.peek() == Some(&Token::Comma) {
+                    self.next();
+                } else {
+                    break;
+                }
+            }
+        }
+        self.expect(Token::RightParen)?;
+
+        let (min, max) = f.arity();
+        if args.len() < min || args.len() > max {
+            anyhow::bail!("Invalid number of arguments for '{}': {}", f, args.len());
+        }
+
+        Ok(Expression::Call(f, args))
+    }
+}
+
+impl TryFrom<&str> for Expression {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut parser = Parser {
+            tokens: tokenize(value)?,
+            pos: 0,
+        };
+        let expression = parser.conditional()?;
+        if let Some(token) = parser.peek() {
+            anyhow::bail!("Unexpected token: {:?}", token);
+        }
+        Ok(expression)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn eval(expression: &str, values: &[(&'static str, Type)]) -> Type {
+        let values: HashMap<&str, Type> = values.iter().cloned().collect();
+        let event = json!({"type": "telemetry", "device": "d1"});
+        Expression::try_from(expression)
+            .unwrap()
+            .evaluate(&Context {
+                values: &values,
+                event: &event,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert!(matches!(
+            eval(
+                "voltage * current",
+                &[("voltage", Type::Float(230.0)), ("current", Type::Float(0.5))]
+            ),
+            Type::Float(v) if v == 115.0
+        ));
+        assert!(matches!(
+            eval("1 + 2 * 3 - -1", &[]),
+            Type::SignedInteger(8)
+        ));
+        assert!(matches!(eval("7 / 2", &[]), Type::Float(v) if v == 3.5));
+    }
+
+    #[test]
+    fn test_conditional() {
+        assert!(matches!(
+            eval(
+                "alarm || level > 3 ? 'critical' : ce.type + '/' + ce.device",
+                &[("alarm", Type::Boolean(false)), ("level", Type::SignedInteger(1))]
+            ),
+            Type::String(s) if s == "telemetry/d1"
+        ));
+    }
+
+    #[test]
+    fn test_functions() {
+        assert!(matches!(
+            eval("round(max(1, x, 2.5), 1)", &[("x", Type::Float(3.24))]),
+            Type::Float(v) if v == 3.2
+        ));
+        assert!(matches!(
+            eval("coalesce(missing, 'default')", &[]),
+            Type::String(s) if s == "default"
+        ));
+        assert!(matches!(eval("abs(missing)", &[]), Type::Null));
+    }
+
+    #[test]
+    fn test_overflow() {
+        let values: HashMap<&str, Type> = vec![("min", Type::SignedInteger(i64::MIN))]
+            .into_iter()
+            .collect();
+        let event = json!({});
+        let context = Context {
+            values: &values,
+            event: &event,
+        };
+        let evaluate =
+            |expression: &str| Expression::try_from(expression).unwrap().evaluate(&context);
+
+        assert!(evaluate("-min").is_err());
+        assert!(evaluate("abs(min)").is_err());
+        // integer arithmetic falls back to floats instead
+        assert!(matches!(evaluate("min - 1"), Ok(Type::Float(_))));
+    }
+
+    #[test]
+    fn test_references() {
+        let e = Expression::try_from("a + B * ce.subject").unwrap();
+        let mut refs: Vec<_> = e.references().into_iter().collect();
+        refs.sort_unstable();
+        assert_eq!(refs, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Expression::try_from("1 +").is_err());
+        assert!(Expression::try_from("exec('rm')").is_err());
+        assert!(Expression::try_from("pow(1)").is_err());
+        assert!(Expression::try_from("a.b").is_err());
+        assert!(Expression::try_from("(1").is_err());
+    }
+}
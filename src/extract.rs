@@ -1,177 +1,861 @@
-use crate::writer::{Insertion, Type};
-use crate::{error::ServiceError, expected::ExpectedType, writer::PostgresWriter};
+use crate::dedup::{Deduplicator, EventKey};
+use crate::writer::Type;
+use crate::{
+    discovery::Discovery,
+    error::{FieldError, ServiceError},
+    expected::{ErrorPolicy, ExpectedType},
+    expression::{Context, Expression},
+    filter::Filter,
+    metrics,
+    payload::{self, Protobuf},
+    pipeline,
+    queue::{Acknowledge, Job, Queue},
+    selector::Selector,
+    senml,
+    transform::Transform,
+    writer::{Layout, PostgresWriter},
+};
 use chrono::Utc;
 use cloudevents::Data;
 use cloudevents::{AttributesReader, Event};
 use serde_json::Value;
+use std::borrow::Cow;
 use std::collections::HashMap;
-use std::convert::TryInto;
+use std::convert::{TryFrom, TryInto};
+use std::sync::Arc;
+use std::time::Instant;
 
 #[derive(Debug, Clone)]
 pub struct Path {
     pub path: String,
-    pub compiled: jsonpath_lib::Compiled,
+    pub selector: Selector,
     pub r#type: ExpectedType,
+    pub policy: ErrorPolicy,
+    pub transform: Transform,
 }
 
+/// A value computed from an expression.
+#[derive(Debug, Clone)]
+pub struct Computed {
+    pub source: String,
+    pub expression: Expression,
+}
+
+/// The outcome of processing an event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// No values were extracted, nothing was written.
+    NoValues,
+    /// Values were extracted and written.
+    Written,
+    /// Values were extracted and queued for writing.
+    Accepted,
+    /// The event was already processed before, nothing was written.
+    Duplicate,
+    /// The event was filtered out, nothing was written.
+    Filtered,
+}
+
+impl Outcome {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NoValues => "no_values",
+            Self::Written => "written",
+            Self::Accepted => "accepted",
+            Self::Duplicate => "duplicate",
+            Self::Filtered => "filtered",
+        }
+    }
+}
+
+/// Named values, extracted from an event.
+type Values<'a> = Vec<(Cow<'a, str>, Type)>;
+
+/// Extracted fields and tags.
+type Fields<'a> = (Values<'a>, Values<'a>);
+
 pub struct Processor {
-    pub writer: PostgresWriter,
+    pub name: String,
+    pub writer: Arc<PostgresWriter>,
+    /// The queue, if events are acknowledged before they are written.
+    pub queue: Option<Queue>,
+    pub deduplicator: Arc<Deduplicator>,
+    pub filter: Filter,
+    pub protobuf: Option<Protobuf>,
+    pub senml: senml::Config,
+    pub discovery: Option<Discovery>,
     pub disable_try_parse: bool,
+    pub report_all_errors: bool,
     pub fields: HashMap<String, Path>,
     pub tags: HashMap<String, Path>,
+    pub computed_fields: HashMap<String, Computed>,
+    pub computed_tags: HashMap<String, Computed>,
 }
 
 impl Processor {
-    pub fn new(writer: PostgresWriter, disable_try_parse: bool) -> anyhow::Result<Self> {
+    /// Create a new processor, reading the mapping from environment variables with the prefix.
+    pub fn new(name: &str, prefix: &str, config: pipeline::Config) -> anyhow::Result<Self> {
+        let default_policy = config.default_error_policy;
+        let senml = config.senml;
+        if senml.mode == senml::Mode::Rows && config.postgresql.layout == Layout::Narrow {
+            // both write one row per value, with the name and value in dedicated columns
+            anyhow::bail!("SenML rows can't be combined with the narrow layout, use SenML pivot");
+        }
+        let discovery = Discovery::new(config.discovery)?;
+
         let mut fields = HashMap::new();
         let mut tags = HashMap::new();
+        let mut computed_fields = HashMap::new();
+        let mut computed_tags = HashMap::new();
 
         for (key, value) in std::env::vars() {
+            let key = match key.strip_prefix(prefix) {
+                Some(key) => key,
+                None => continue,
+            };
+
             if let Some(field) = key.strip_prefix("FIELD_") {
                 log::debug!("Adding field - {} -> {}", field, value);
-                let compiled = jsonpath_lib::Compiled::compile(&value)
-                    .map_err(|err| anyhow::anyhow!("Failed to parse JSON path: {}", err))?;
+                let selector = Selector::try_from(value.as_str())?;
 
                 // find expected type for the field
-                let expected_type = std::env::var(format!("TYPE_FIELD_{}", field)).try_into()?;
+                let expected_type =
+                    std::env::var(format!("{}TYPE_FIELD_{}", prefix, field)).try_into()?;
+                // find error policy for the field
+                let policy = ErrorPolicy::from_env(
+                    std::env::var(format!("{}POLICY_FIELD_{}", prefix, field)),
+                    default_policy,
+                )?;
+                // find transformation for the field
+                let transform: Transform =
+                    std::env::var(format!("{}TRANSFORM_FIELD_{}", prefix, field)).try_into()?;
                 fields.insert(
                     field.to_lowercase(),
                     Path {
                         path: value,
-                        compiled,
+                        selector,
                         r#type: expected_type,
+                        policy,
+                        transform,
                     },
                 );
             } else if let Some(tag) = key.strip_prefix("TAG_") {
                 log::debug!("Adding tag - {} -> {}", tag, value);
-                let compiled = jsonpath_lib::Compiled::compile(&value)
-                    .map_err(|err| anyhow::anyhow!("Failed to parse JSON path: {}", err))?;
+                let selector = Selector::try_from(value.as_str())?;
 
                 // find expected type for the tag
-                let expected_type = std::env::var(format!("TYPE_TAG_{}", tag)).try_into()?;
+                let expected_type =
+                    std::env::var(format!("{}TYPE_TAG_{}", prefix, tag)).try_into()?;
+                // find error policy for the tag
+                let policy = ErrorPolicy::from_env(
+                    std::env::var(format!("{}POLICY_TAG_{}", prefix, tag)),
+                    default_policy,
+                )?;
+                // find transformation for the tag
+                let transform: Transform =
+                    std::env::var(format!("{}TRANSFORM_TAG_{}", prefix, tag)).try_into()?;
                 tags.insert(
                     tag.to_lowercase(),
                     Path {
                         path: value,
-                        compiled,
+                        selector,
                         r#type: expected_type,
+                        policy,
+                        transform,
+                    },
+                );
+            } else if let Some(field) = key.strip_prefix("COMPUTE_FIELD_") {
+                log::debug!("Adding computed field - {} -> {}", field, value);
+                let expression = Expression::try_from(value.as_str())
+                    .map_err(|err| anyhow::anyhow!("Failed to parse expression: {}", err))?;
+                computed_fields.insert(
+                    field.to_lowercase(),
+                    Computed {
+                        source: value,
+                        expression,
+                    },
+                );
+            } else if let Some(tag) = key.strip_prefix("COMPUTE_TAG_") {
+                log::debug!("Adding computed tag - {} -> {}", tag, value);
+                let expression = Expression::try_from(value.as_str())
+                    .map_err(|err| anyhow::anyhow!("Failed to parse expression: {}", err))?;
+                computed_tags.insert(
+                    tag.to_lowercase(),
+                    Computed {
+                        source: value,
+                        expression,
                     },
                 );
             }
         }
 
+        // validate computed values
+
+        for (name, computed) in computed_fields.iter().chain(computed_tags.iter()) {
+            if fields.contains_key(name) || tags.contains_key(name) {
+                anyhow::bail!("Computed value '{}' conflicts with extracted value", name);
+            }
+            for reference in computed.expression.references() {
+                // with SenML or discovery, fields are only known when processing the payload
+                if senml.mode == senml::Mode::Disabled
+                    && discovery.is_none()
+                    && !fields.contains_key(reference)
+                    && !tags.contains_key(reference)
+                {
+                    anyhow::bail!(
+                        "Computed value '{}' references unknown field or tag: {}",
+                        name,
+                        reference
+                    );
+                }
+            }
+        }
+
+        let writer = Arc::new(PostgresWriter::new(name, config.postgresql)?);
+        let deduplicator = Arc::new(Deduplicator::new(config.deduplication));
+        let queue = match config.acknowledge {
+            Acknowledge::Async => Some(Queue::new(
+                name,
+                writer.clone(),
+                deduplicator.clone(),
+                config.queue,
+            )?),
+            Acknowledge::Sync => None,
+        };
+
         Ok(Processor {
+            name: name.to_string(),
             writer,
+            queue,
+            deduplicator,
+            filter: Filter::from_env(prefix)?,
+            protobuf: Protobuf::new(config.protobuf)?,
+            senml,
+            discovery,
             fields,
             tags,
-            disable_try_parse,
+            computed_fields,
+            computed_tags,
+            disable_try_parse: config.disable_try_parse,
+            report_all_errors: config.report_all_errors,
         })
     }
 
-    pub async fn process(&self, event: Event) -> Result<usize, ServiceError> {
+    /// Start shutting down, while still accepting new events.
+    pub fn start_draining(&self) {
+        if let Some(queue) = &self.queue {
+            queue.start_draining();
+        }
+        self.writer.start_draining();
+    }
+
+    /// Stop accepting new events.
+    pub fn close(&self) {
+        // queued events are still written, until shutting down
+        match &self.queue {
+            Some(queue) => queue.close(),
+            None => self.writer.close(),
+        }
+    }
+
+    /// Drain pending events, until the deadline is reached.
+    pub async fn shutdown(&self, deadline: Instant) {
+        if let Some(queue) = &self.queue {
+            queue.shutdown(deadline).await;
+        }
+        self.writer.shutdown(deadline).await;
+    }
+
+    /// Process an event, only queueing it for writing if acknowledging asynchronously.
+    pub async fn process(&self, event: Event) -> Result<Outcome, ServiceError> {
+        self.process_with(event, self.queue.as_ref()).await
+    }
+
+    /// Process an event, always writing it before returning.
+    ///
+    /// Sources acknowledge messages once this returns, so they must not be acknowledged before the
+    /// event was written, not even when the HTTP endpoint acknowledges asynchronously.
+    pub async fn process_sync(&self, event: Event) -> Result<Outcome, ServiceError> {
+        self.process_with(event, None).await
+    }
+
+    async fn process_with(
+        &self,
+        event: Event,
+        queue: Option<&Queue>,
+    ) -> Result<Outcome, ServiceError> {
+        let result = self.process_event(event, queue).await;
+
+        let outcome = match &result {
+            Ok(outcome) => outcome.as_str(),
+            Err(_) => "failed",
+        };
+        metrics::EVENTS
+            .with_label_values(&[&self.name, outcome])
+            .inc();
+
+        result
+    }
+
+    async fn process_event(
+        &self,
+        event: Event,
+        queue: Option<&Queue>,
+    ) -> Result<Outcome, ServiceError> {
+        if !self.filter.accepts_attributes(&event) {
+            log::debug!("Event filtered by attributes: {}", event.id());
+            return Ok(Outcome::Filtered);
+        }
+
+        // also required without deduplication, to retry failed writes to secondary targets only
+        let key = EventKey::from(&event);
+
+        if self.deduplicator.is_duplicate(&key) {
+            log::debug!("Skipping duplicate event: {:?}", key);
+            return Ok(Outcome::Duplicate);
+        }
+
         let data: Option<&Data> = event.data();
-        let json = parse_payload(data)?;
+        let dataschema = event.dataschema().map(ToString::to_string);
+        let json = parse_payload(
+            event.datacontenttype(),
+            dataschema.as_deref(),
+            data,
+            self.protobuf.as_ref(),
+        )?;
+
+        if !self.filter.accepts(&event, &json) {
+            log::debug!("Event filtered: {}", event.id());
+            return Ok(Outcome::Filtered);
+        }
+
         let timestamp = event.time().cloned().unwrap_or_else(Utc::now);
 
-        let insertion = self.writer.new_insertion(timestamp).await?;
+        // when reporting all errors, collect them instead of failing early
 
-        // process values with payload only
+        let mut errors = Vec::new();
+        let mut diagnostics = match self.report_all_errors {
+            true => Some(&mut errors),
+            false => None,
+        };
 
-        let (insertion, num) = self.add_values(insertion, &json)?;
+        // process values with payload only, either using the paths or from SenML records
+
+        let rows = match self.senml.mode {
+            senml::Mode::Disabled => {
+                let mut fields = extract_values(
+                    &self.name,
+                    &self.fields,
+                    self.disable_try_parse,
+                    &json,
+                    diagnostics.as_deref_mut(),
+                )?;
+                if let Some(discovery) = &self.discovery {
+                    let discovered = self.discover(discovery, &json).await?;
+                    fields.extend(
+                        discovered
+                            .into_iter()
+                            .map(|(name, value)| (Cow::Owned(name), value)),
+                    );
+                }
+                vec![(timestamp, fields)]
+            }
+            _ => self
+                .senml
+                .rows(senml::parse(&json, timestamp)?, |name| self.reserved(name))?,
+        };
 
         // create full events JSON for tags
 
-        let event_json = serde_json::to_value(event)
+        let event_json = serde_json::to_value(&event)
             .map_err(|err| ServiceError::PayloadParse(err.to_string()))?;
-        let (insertion, _) = self.add_tags(insertion, &event_json)?;
+        let tags = extract_values(
+            &self.name,
+            &self.tags,
+            self.disable_try_parse,
+            &event_json,
+            diagnostics.as_deref_mut(),
+        )?;
+
+        // evaluate computed values, based on the extracted ones, and skip rows without values, values
+        // written as NULL by policy still count
+
+        let mut computed = Vec::with_capacity(rows.len());
+        for (timestamp, fields) in rows {
+            let (fields, tags) = self.compute(
+                fields,
+                tags.clone(),
+                &event_json,
+                diagnostics.as_deref_mut(),
+            )?;
+            if !fields.is_empty() {
+                computed.push((timestamp, fields, tags));
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(ServiceError::Fields(errors));
+        }
+
+        if computed.is_empty() {
+            return Ok(Outcome::NoValues);
+        }
+
+        let mut insertions = Vec::with_capacity(computed.len());
+        for (timestamp, fields, tags) in computed {
+            insertions.extend(self.writer.new_insertions(timestamp, fields, tags).await?);
+        }
+
+        let processed = self.deduplicator.table();
+
+        if let Some(queue) = queue {
+            queue.enqueue(Job {
+                insertions,
+                key,
+                processed: processed.map(ToString::to_string),
+            })?;
+            // the event is marked as processed by the queue, once it was written
+            return Ok(Outcome::Accepted);
+        }
 
-        if num > 0 {
-            self.writer.write(insertion).await?;
+        if !self.writer.write(insertions, Some(&key), processed).await? {
+            log::debug!("Skipping duplicate event: {:?}", key);
+            return Ok(Outcome::Duplicate);
         }
-        Ok(num)
+
+        self.deduplicator.mark(key);
+
+        Ok(Outcome::Written)
     }
 
-    fn add_values<'a, I>(&self, insertion: I, json: &Value) -> Result<(I, usize), ServiceError>
-    where
-        I: Insertion<'a>,
-    {
-        add_to_query(
-            insertion,
-            self.disable_try_parse,
-            &self.fields,
-            json,
-            |insertion, field, value| insertion.add_field(field, value),
-        )
-    }
-
-    fn add_tags<'a, I>(&self, insertion: I, json: &Value) -> Result<(I, usize), ServiceError>
-    where
-        I: Insertion<'a>,
-    {
-        add_to_query(
-            insertion,
-            self.disable_try_parse,
-            &self.tags,
-            json,
-            |insertion, field, value| insertion.add_tag(field, value),
-        )
+    /// Discover fields, which are not configured otherwise.
+    async fn discover(
+        &self,
+        discovery: &Discovery,
+        json: &Value,
+    ) -> Result<Vec<(String, Type)>, ServiceError> {
+        discovery
+            .discover(json, &self.writer, |name| self.reserved(name))
+            .await
+    }
+
+    /// Check if a column name is taken by the time column, or a configured field or tag.
+    fn reserved(&self, name: &str) -> bool {
+        name == self.writer.time_column()
+            || self.fields.contains_key(name)
+            || self.tags.contains_key(name)
+            || self.computed_fields.contains_key(name)
+            || self.computed_tags.contains_key(name)
+    }
+
+    /// Evaluate the computed fields and tags, and add them to the extracted ones.
+    fn compute<'a>(
+        &'a self,
+        mut fields: Values<'a>,
+        mut tags: Values<'a>,
+        event: &Value,
+        mut diagnostics: Option<&mut Vec<FieldError>>,
+    ) -> Result<Fields<'a>, ServiceError> {
+        if self.computed_fields.is_empty() && self.computed_tags.is_empty() {
+            return Ok((fields, tags));
+        }
+
+        let values: HashMap<&str, Type> = fields
+            .iter()
+            .chain(tags.iter())
+            .map(|(name, value)| (name.as_ref(), value.clone()))
+            .collect();
+        let context = Context {
+            values: &values,
+            event,
+        };
+
+        // the context borrows the extracted names, so computed values are added afterwards
+        let mut computed_fields = Vec::new();
+        let mut computed_tags = Vec::new();
+        for (items, target) in [
+            (&self.computed_fields, &mut computed_fields),
+            (&self.computed_tags, &mut computed_tags),
+        ] {
+            for (name, computed) in items {
+                match computed.expression.evaluate(&context) {
+                    // skip NULL results
+                    Ok(Type::Null) => {}
+                    Ok(value) => target.push((Cow::Borrowed(name.as_str()), value)),
+                    Err(err) => match diagnostics.as_deref_mut() {
+                        Some(diagnostics) => diagnostics.push(FieldError {
+                            field: name.to_string(),
+                            path: computed.source.clone(),
+                            value: None,
+                            expected_type: ExpectedType::None.to_string(),
+                            reason: err.to_string(),
+                        }),
+                        None => return Err(err),
+                    },
+                }
+            }
+        }
+
+        fields.extend(computed_fields);
+        tags.extend(computed_tags);
+        Ok((fields, tags))
     }
 }
 
-fn add_to_query<'a, I, F>(
-    mut query: I,
+/// Extract the values of all paths.
+fn extract_values<'a>(
+    pipeline: &str,
+    items: &'a HashMap<String, Path>,
     disable_try_parse: bool,
-    items: &HashMap<String, Path>,
     json: &Value,
-    f: F,
-) -> Result<(I, usize), ServiceError>
-where
-    I: Insertion<'a>,
-    F: Fn(I, &String, Type) -> I,
-{
-    let mut num = 0;
-
-    let mut f = |query, field, value| {
-        num += 1;
-        f(query, field, value)
-    };
-
-    for (ref field, ref path) in items {
-        let sel = path
-            .compiled
-            .select(&json)
-            .map_err(|err| ServiceError::Selector(err.to_string()))?;
-
-        query = match sel.as_slice() {
+    mut diagnostics: Option<&mut Vec<FieldError>>,
+) -> Result<Values<'a>, ServiceError> {
+    let mut result = Vec::with_capacity(items.len());
+
+    for (field, path) in items {
+        match select_value(path, json, disable_try_parse) {
             // no value, don't add
-            [] => Ok(query),
+            Ok(None) => {}
             // single value, process
-            [v] => Ok(f(
-                query,
-                field,
-                path.r#type.convert(v, path, disable_try_parse)?,
-            )),
-            // multiple values, error
-            [..] => Err(ServiceError::Selector(format!(
-                "Selector found more than one value: {}",
-                sel.len()
-            ))),
-        }?;
+            Ok(Some(value)) => result.push((Cow::Borrowed(field.as_str()), value)),
+            // failed, apply policy
+            Err((err, _)) if path.policy != ErrorPolicy::Fail => {
+                log::debug!(
+                    "Failed processing '{}', applying policy '{}': {}",
+                    field,
+                    path.policy,
+                    err
+                );
+                metrics::FIELDS_SKIPPED
+                    .with_label_values(&[pipeline, &path.policy.to_string()])
+                    .inc();
+                if let ErrorPolicy::Null = path.policy {
+                    result.push((Cow::Borrowed(field.as_str()), Type::Null));
+                }
+            }
+            // failed, either record or abort
+            Err((err, value)) => match diagnostics.as_deref_mut() {
+                Some(diagnostics) => diagnostics.push(FieldError {
+                    field: field.to_string(),
+                    path: path.path.clone(),
+                    value,
+                    expected_type: path.r#type.to_string(),
+                    reason: err.to_string(),
+                }),
+                None => return Err(err),
+            },
+        }
     }
 
-    Ok((query, num))
+    Ok(result)
 }
 
-fn parse_payload(data: Option<&Data>) -> Result<Value, ServiceError> {
-    match data {
-        Some(Data::Json(value)) => Ok(value.clone()),
-        Some(Data::String(s)) => serde_json::from_str::<Value>(&s)
-            .map_err(|err| ServiceError::PayloadParse(err.to_string())),
+/// Select and convert the value of a path.
+///
+/// In the case of an error, the selected value is returned as well, if there was one.
+fn select_value(
+    path: &Path,
+    json: &Value,
+    disable_try_parse: bool,
+) -> Result<Option<Type>, (ServiceError, Option<Value>)> {
+    let sel = path.selector.select(json).map_err(|err| (err, None))?;
 
-        Some(Data::Binary(b)) => serde_json::from_slice::<Value>(&b)
+    match sel.as_slice() {
+        // no value
+        [] => Ok(None),
+        // single value, convert and transform
+        [v] => path
+            .r#type
+            .convert(v, path, disable_try_parse)
+            .and_then(|value| path.transform.apply(value))
+            .map(Some)
+            .map_err(|err| (err, Some(v.clone().into_owned()))),
+        // multiple values, error
+        [..] => Err((
+            ServiceError::Selector(format!("Selector found more than one value: {}", sel.len())),
+            Some(Value::Array(
+                sel.iter().map(|v| v.clone().into_owned()).collect(),
+            )),
+        )),
+    }
+}
+
+fn parse_payload(
+    content_type: Option<&str>,
+    dataschema: Option<&str>,
+    data: Option<&Data>,
+    protobuf: Option<&Protobuf>,
+) -> Result<Value, ServiceError> {
+    let mut parameters = content_type
+        .into_iter()
+        .flat_map(|content_type| content_type.split(';'));
+    let content_type = parameters
+        .next()
+        .map(|content_type| content_type.trim().to_lowercase());
+    let charset = parameters
+        .filter_map(|parameter| parameter.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("charset"))
+        .map(|(_, value)| value.trim().trim_matches('"').to_lowercase());
+
+    match (content_type.as_deref(), data) {
+        (Some("application/cbor"), Some(Data::Binary(b))) => payload::from_cbor(b),
+        (Some("application/cbor"), Some(Data::String(s))) => payload::from_cbor(&binary(s)?),
+        (
+            Some("application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack"),
+            Some(Data::Binary(b)),
+        ) => payload::from_msgpack(b),
+        (
+            Some("application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack"),
+            Some(Data::String(s)),
+        ) => payload::from_msgpack(&binary(s)?),
+        (Some("application/protobuf" | "application/x-protobuf"), data) => {
+            let protobuf = protobuf.ok_or_else(|| {
+                ServiceError::PayloadParse("No protobuf descriptor set configured".into())
+            })?;
+            match data {
+                Some(Data::Binary(b)) => protobuf.decode(dataschema, b),
+                Some(Data::String(s)) => protobuf.decode(dataschema, &binary(s)?),
+                _ => Err(ServiceError::PayloadParse(
+                    "Unknown protobuf payload".to_string(),
+                )),
+            }
+        }
+
+        (_, Some(Data::Json(value))) => Ok(value.clone()),
+        (_, Some(Data::String(s))) => serde_json::from_str::<Value>(s)
             .map_err(|err| ServiceError::PayloadParse(err.to_string())),
+
+        (_, Some(Data::Binary(b))) => {
+            serde_json::from_str::<Value>(&decode(b, charset.as_deref())?)
+                .map_err(|err| ServiceError::PayloadParse(err.to_string()))
+        }
         _ => Err(ServiceError::PayloadParse(
             "Unknown event payload".to_string(),
         )),
     }
 }
+
+/// Get the bytes of a binary payload, carried as string.
+///
+/// The content type of the payload is a binary one, so in the structured mode, the string must
+/// carry it base64 encoded.
+fn binary(s: &str) -> Result<Vec<u8>, ServiceError> {
+    base64::decode(s).map_err(|err| {
+        ServiceError::PayloadParse(format!(
+            "Binary payload, carried as string, must be base64 encoded: {}",
+            err
+        ))
+    })
+}
+
+/// Decode a text payload, using the charset of its content type, UTF-8 by default.
+fn decode<'a>(b: &'a [u8], charset: Option<&str>) -> Result<Cow<'a, str>, ServiceError> {
+    match charset {
+        None | Some("utf-8" | "utf8" | "us-ascii") => std::str::from_utf8(b)
+            .map(Cow::Borrowed)
+            .map_err(|err| ServiceError::PayloadParse(err.to_string())),
+        // all ISO-8859-1 code points map to the same Unicode code points
+        Some("iso-8859-1" | "latin1") => Ok(Cow::Owned(b.iter().map(|&c| c as char).collect())),
+        Some(charset) => Err(ServiceError::PayloadParse(format!(
+            "Unsupported charset: {}",
+            charset
+        ))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cloudevents::{EventBuilder, EventBuilderV10};
+    use serde_json::json;
+
+    fn path(path: &str, r#type: ExpectedType, policy: ErrorPolicy) -> Path {
+        Path {
+            path: path.into(),
+            selector: Selector::try_from(path).unwrap(),
+            r#type,
+            policy,
+            transform: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_report_all_errors() {
+        let mut items = HashMap::new();
+        items.insert(
+            "temp".to_string(),
+            path("$.temp", ExpectedType::Float, ErrorPolicy::Fail),
+        );
+        items.insert(
+            "count".to_string(),
+            path("$.count", ExpectedType::UnsignedInteger, ErrorPolicy::Fail),
+        );
+        items.insert(
+            "ok".to_string(),
+            path("$.ok", ExpectedType::Boolean, ErrorPolicy::Fail),
+        );
+        let json = json!({"temp": "hot", "count": -1, "ok": true});
+
+        // without diagnostics, the first error fails processing
+        assert!(extract_values("test", &items, true, &json, None).is_err());
+
+        // with diagnostics, all errors are collected, and the remaining values are extracted
+        let mut errors = Vec::new();
+        let values = extract_values("test", &items, true, &json, Some(&mut errors)).unwrap();
+        assert_eq!(values.len(), 1);
+        assert_eq!(values[0].0, "ok");
+
+        errors.sort_by(|a, b| a.field.cmp(&b.field));
+        let fields: Vec<_> = errors
+            .iter()
+            .map(|error| {
+                (
+                    error.field.as_str(),
+                    error.path.as_str(),
+                    error.value.clone(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                ("count", "$.count", Some(json!(-1))),
+                ("temp", "$.temp", Some(json!("hot"))),
+            ]
+        );
+        assert_eq!(
+            ServiceError::Fields(errors).to_string(),
+            "Failed processing 2 field(s)"
+        );
+    }
+
+    #[test]
+    fn test_error_policy() {
+        let mut items = HashMap::new();
+        items.insert(
+            "skipped".to_string(),
+            path("$.skipped", ExpectedType::Float, ErrorPolicy::SkipField),
+        );
+        items.insert(
+            "nulled".to_string(),
+            path("$.nulled", ExpectedType::Float, ErrorPolicy::Null),
+        );
+        items.insert(
+            "temp".to_string(),
+            path("$.temp", ExpectedType::Float, ErrorPolicy::Fail),
+        );
+        let json = json!({"skipped": "a", "nulled": "b", "temp": 21.5});
+
+        let mut errors = Vec::new();
+        let mut values = extract_values("test", &items, true, &json, Some(&mut errors)).unwrap();
+        values.sort_by(|a, b| a.0.cmp(&b.0));
+
+        // failures handled by policy are not reported
+        assert!(errors.is_empty());
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[0].0, "nulled");
+        assert!(matches!(values[0].1, Type::Null));
+        assert_eq!(values[1].0, "temp");
+        assert!(matches!(values[1].1, Type::Float(v) if v == 21.5));
+
+        // failing the event is not affected by the policies of other fields
+        let json = json!({"skipped": "a", "nulled": "b", "temp": "c"});
+        assert!(extract_values("test", &items, true, &json, None).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_null_row() {
+        let config: pipeline::Config = serde_json::from_value(json!({
+            "postgresql": {
+                "table": "test",
+                "connection": {"host": "localhost", "port": 1, "dbname": "test"},
+            },
+            "acknowledge": "async",
+        }))
+        .unwrap();
+        let mut processor = Processor::new("test-null-row", "TEST_NULL_ROW_", config).unwrap();
+        processor.fields.insert(
+            "temp".into(),
+            path("$.temp", ExpectedType::Float, ErrorPolicy::Null),
+        );
+
+        let event = EventBuilderV10::new()
+            .id("1")
+            .source("test")
+            .ty("test")
+            .data("application/json", json!({"temp": "hot"}))
+            .build()
+            .unwrap();
+
+        // the row is written, with NULL for the failing field
+        assert!(matches!(
+            processor.process(event).await,
+            Ok(Outcome::Accepted)
+        ));
+        assert_eq!(
+            metrics::FIELDS_SKIPPED
+                .with_label_values(&["test-null-row", "null"])
+                .get(),
+            1
+        );
+        processor.shutdown(Instant::now()).await;
+    }
+
+    #[actix_web::test]
+    async fn test_retry_without_deduplication() {
+        let connection = json!({"host": "localhost", "port": 1, "dbname": "test"});
+        let config: pipeline::Config = serde_json::from_value(json!({
+            "postgresql": {
+                "table": "test",
+                "connection": connection,
+                "fan_out": "all",
+                "secondaries": {"a": {"connection": connection}},
+            },
+        }))
+        .unwrap();
+        let mut processor = Processor::new("test-retry", "TEST_RETRY_", config).unwrap();
+        processor.fields.insert(
+            "temp".into(),
+            path("$.temp", ExpectedType::Float, ErrorPolicy::Fail),
+        );
+
+        let event = EventBuilderV10::new()
+            .id("1")
+            .source("test")
+            .ty("test")
+            .data("application/json", json!({"temp": 21.5}))
+            .build()
+            .unwrap();
+
+        // the retry of an event, which only failed on a secondary target, is only written to that
+        processor.writer.set_missing(
+            EventKey {
+                source: "test".into(),
+                id: "1".into(),
+            },
+            vec!["a".into()],
+        );
+        assert!(matches!(
+            processor.process(event).await,
+            Err(ServiceError::Target(msg)) if msg.starts_with("a:")
+        ));
+    }
+
+    #[test]
+    fn test_senml_rows_narrow_layout() {
+        let config = |mode: &str| -> pipeline::Config {
+            serde_json::from_value(json!({
+                "postgresql": {
+                    "table": "test",
+                    "connection": {"host": "localhost", "dbname": "test"},
+                    "layout": "narrow",
+                },
+                "senml": {"mode": mode},
+            }))
+            .unwrap()
+        };
+
+        assert!(Processor::new("test", "TEST_SENML_NARROW_", config("rows")).is_err());
+        assert!(Processor::new("test", "TEST_SENML_NARROW_", config("pivot")).is_ok());
+    }
+}
@@ -0,0 +1,212 @@
+use crate::selector::Selector;
+use cloudevents::Event;
+use regex::Regex;
+use serde_json::Value;
+use std::convert::TryFrom;
+
+/// A rule matching an event.
+#[derive(Debug, Clone)]
+pub enum Rule {
+    /// Match a cloud event attribute (or extension) against a pattern.
+    Attribute { name: String, pattern: Regex },
+    /// Match if the selector selects at least one value from the payload, which is not `null`
+    /// or `false`.
+    Payload { path: String, selector: Selector },
+}
+
+impl Rule {
+    /// Check if the rule matches, considering attributes only.
+    fn matches_attributes(&self, event: &Event) -> bool {
+        match self {
+            Rule::Attribute { name, pattern } => event
+                .iter()
+                .find(|(n, _)| *n == name.as_str())
+                .map(|(_, value)| pattern.is_match(&value.to_string()))
+                .unwrap_or_default(),
+            Rule::Payload { .. } => false,
+        }
+    }
+
+    fn matches(&self, event: &Event, payload: &Value) -> bool {
+        match self {
+            Rule::Attribute { .. } => self.matches_attributes(event),
+            Rule::Payload { path, selector } => match selector.select(payload) {
+                Ok(sel) => sel
+                    .iter()
+                    .any(|v| !matches!(v.as_ref(), Value::Null | Value::Bool(false))),
+                Err(err) => {
+                    log::debug!("Failed to evaluate filter path '{}': {}", path, err);
+                    false
+                }
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for Rule {
+    type Error = anyhow::Error;
+
+    /// Parse a rule, either a selector (a JSON path starting with `$`, or a prefixed selector),
+    /// or `<attribute>=<pattern>`, where the pattern may contain `*` as wildcard.
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if ["$", "jsonpath:", "pointer:", "jmespath:"]
+            .iter()
+            .any(|prefix| value.starts_with(prefix))
+        {
+            let selector = Selector::try_from(value.as_str())?;
+            return Ok(Rule::Payload {
+                path: value,
+                selector,
+            });
+        }
+
+        let (name, pattern) = value
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected '<attribute>=<pattern>': {}", value))?;
+
+        Ok(Rule::Attribute {
+            name: name.trim().to_lowercase(),
+            pattern: glob(pattern)?,
+        })
+    }
+}
+
+/// Compile a pattern, which may contain `*` as wildcard, into a regular expression.
+pub fn glob(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!(
+        "^{}$",
+        regex::escape(pattern).replace(r"\*", ".*")
+    ))
+}
+
+/// Rules for including or excluding events, before extracting values.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    pub include: Vec<Rule>,
+    pub exclude: Vec<Rule>,
+}
+
+impl Filter {
+    /// Read the rules from environment variables, with the prefix.
+    pub fn from_env(prefix: &str) -> anyhow::Result<Self> {
+        let mut filter = Filter::default();
+
+        for (key, value) in std::env::vars() {
+            let key = match key.strip_prefix(prefix) {
+                Some(key) => key,
+                None => continue,
+            };
+
+            if let Some(name) = key.strip_prefix("FILTER_INCLUDE_") {
+                log::debug!("Adding include filter - {} -> {}", name, value);
+                filter.include.push(Rule::try_from(value)?);
+            } else if let Some(name) = key.strip_prefix("FILTER_EXCLUDE_") {
+                log::debug!("Adding exclude filter - {} -> {}", name, value);
+                filter.exclude.push(Rule::try_from(value)?);
+            }
+        }
+
+        Ok(filter)
+    }
+
+    /// Check if the event is accepted, based on its attributes only.
+    ///
+    /// This allows to drop events early, without processing their payload. Payload include rules
+    /// can't be evaluated yet, so the event may still be included by them.
+    pub fn accepts_attributes(&self, event: &Event) -> bool {
+        if self
+            .exclude
+            .iter()
+            .any(|rule| rule.matches_attributes(event))
+        {
+            return false;
+        }
+        self.include.is_empty()
+            || self.include.iter().any(|rule| match rule {
+                Rule::Attribute { .. } => rule.matches_attributes(event),
+                Rule::Payload { .. } => true,
+            })
+    }
+
+    /// Check if the event is accepted, based on its attributes and payload.
+    pub fn accepts(&self, event: &Event, payload: &Value) -> bool {
+        if self.exclude.iter().any(|rule| rule.matches(event, payload)) {
+            return false;
+        }
+        self.include.is_empty() || self.include.iter().any(|rule| rule.matches(event, payload))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cloudevents::{EventBuilder, EventBuilderV10};
+    use serde_json::json;
+
+    fn event(ty: &str) -> Event {
+        EventBuilderV10::new()
+            .id("1")
+            .source("test")
+            .ty(ty)
+            .extension("device", "d1")
+            .build()
+            .unwrap()
+    }
+
+    fn filter(include: &[&str], exclude: &[&str]) -> Filter {
+        let rules = |rules: &[&str]| {
+            rules
+                .iter()
+                .map(|rule| Rule::try_from(rule.to_string()).unwrap())
+                .collect()
+        };
+        Filter {
+            include: rules(include),
+            exclude: rules(exclude),
+        }
+    }
+
+    #[test]
+    fn test_include() {
+        let filter = filter(&["type=telemetry", "device=d2"], &[]);
+
+        assert!(filter.accepts_attributes(&event("telemetry")));
+        assert!(filter.accepts(&event("telemetry"), &json!({})));
+        // rejected early, without the payload
+        assert!(!filter.accepts_attributes(&event("status")));
+        assert!(!filter.accepts(&event("status"), &json!({})));
+
+        // payload rules can only be evaluated with the payload
+        let filter = self::filter(&["type=telemetry", "$.temp"], &[]);
+        assert!(filter.accepts_attributes(&event("status")));
+        assert!(filter.accepts(&event("status"), &json!({"temp": 21.5})));
+        assert!(!filter.accepts(&event("status"), &json!({"temp": null})));
+    }
+
+    #[test]
+    fn test_exclude() {
+        let filter = filter(&[], &["device=d1", "$.test"]);
+
+        assert!(!filter.accepts_attributes(&event("telemetry")));
+        assert!(!filter.accepts(&event("telemetry"), &json!({})));
+
+        let filter = self::filter(&[], &["device=d2", "$.test"]);
+        assert!(filter.accepts_attributes(&event("telemetry")));
+        assert!(filter.accepts(&event("telemetry"), &json!({"test": false})));
+        assert!(!filter.accepts(&event("telemetry"), &json!({"test": true})));
+    }
+
+    #[test]
+    fn test_glob() {
+        let pattern = glob("sensor.*.v1").unwrap();
+        assert!(pattern.is_match("sensor.temp.v1"));
+        assert!(pattern.is_match("sensor..v1"));
+        // other characters are literal, and the pattern is anchored
+        assert!(!pattern.is_match("sensorXtemp.v1"));
+        assert!(!pattern.is_match("io.sensor.temp.v1"));
+
+        let filter = filter(&["type=io.drogue.*"], &[]);
+        assert!(filter.accepts_attributes(&event("io.drogue.event.v1")));
+        assert!(!filter.accepts_attributes(&event("io.other.event.v1")));
+    }
+}
@@ -1,7 +1,19 @@
-use crate::extract::Processor;
-use actix_web::{post, web, HttpResponse};
+use crate::{
+    error::{ErrorResponse, ServiceError},
+    extract::{Outcome, Processor},
+    metrics,
+    ratelimit::{self, Key, RateLimiter},
+    tls::TlsConfig,
+};
+use actix_web::{
+    http::header::{HeaderValue, RETRY_AFTER},
+    web, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
 use cloudevents::Event;
 use serde::Deserialize;
+use std::fmt;
+use std::time::Duration;
+use tokio::sync::Semaphore;
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct EndpointConfig {
@@ -9,11 +21,39 @@ pub struct EndpointConfig {
     pub max_json_payload_size: usize,
     #[serde(default = "default_bind_addr")]
     pub bind_addr: String,
+    /// The maximum number of events processed concurrently, across all pipelines.
+    pub max_concurrent_events: Option<usize>,
+    /// The delay a caller should wait, before retrying a rejected event.
+    #[serde(default = "default_retry_after", with = "humantime_serde")]
+    pub retry_after: Duration,
+    /// Limit the rate of events per client.
+    pub rate_limit: Option<ratelimit::Config>,
+    /// Serve HTTPS instead of HTTP.
+    pub tls: Option<TlsConfig>,
+}
 
+/// The credentials a caller has to provide, for a pipeline.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AuthConfig {
     pub username: Option<String>,
     pub password: Option<String>,
 
     pub token: Option<String>,
+
+    /// Comma separated names of client certificates, allowed to call. A certificate matches by
+    /// its subject common name, or one of its subject alternative names.
+    pub clients: Option<String>,
+}
+
+impl AuthConfig {
+    pub fn clients(&self) -> Vec<String> {
+        self.clients
+            .iter()
+            .flat_map(|clients| clients.split(','))
+            .map(|client| client.trim().to_string())
+            .filter(|client| !client.is_empty())
+            .collect()
+    }
 }
 
 impl Default for EndpointConfig {
@@ -21,9 +61,10 @@ impl Default for EndpointConfig {
         Self {
             max_json_payload_size: default_max_json_payload_size(),
             bind_addr: default_bind_addr(),
-            username: None,
-            password: None,
-            token: None,
+            max_concurrent_events: None,
+            retry_after: default_retry_after(),
+            rate_limit: None,
+            tls: None,
         }
     }
 }
@@ -38,15 +79,478 @@ fn default_max_json_payload_size() -> usize {
     64 * 1024
 }
 
-#[post("/")]
+#[inline]
+fn default_retry_after() -> Duration {
+    Duration::from_secs(1)
+}
+
+/// The authenticated caller of a pipeline, stored in the extensions of a request.
+///
+/// Callers are authenticated by each pipeline, the same name may identify different callers of
+/// different pipelines.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Principal {
+    pub pipeline: String,
+    pub name: String,
+}
+
+impl Principal {
+    pub fn new(pipeline: &str, name: impl Into<String>) -> Self {
+        Self {
+            pipeline: pipeline.to_string(),
+            name: name.into(),
+        }
+    }
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.pipeline, self.name)
+    }
+}
+
+/// Limits the number of events processed concurrently, rejecting events once saturated, and the
+/// rate of events per client.
+pub struct Limiter {
+    permits: Option<Semaphore>,
+    rate: Option<RateLimiter>,
+    retry_after: Duration,
+}
+
+impl Limiter {
+    pub fn new(config: &EndpointConfig) -> anyhow::Result<Self> {
+        if config.max_concurrent_events == Some(0) {
+            // would reject all events
+            anyhow::bail!("The maximum number of concurrent events must be greater than zero");
+        }
+
+        Ok(Self {
+            permits: config.max_concurrent_events.map(Semaphore::new),
+            rate: config
+                .rate_limit
+                .clone()
+                .map(RateLimiter::new)
+                .transpose()?,
+            retry_after: config.retry_after,
+        })
+    }
+
+    /// Add the `Retry-After` header to a response, rejecting an event.
+    fn retry_later(&self, response: HttpResponse) -> HttpResponse {
+        retry_after(response, self.retry_after)
+    }
+}
+
+fn retry_after(mut response: HttpResponse, delay: Duration) -> HttpResponse {
+    // round up, retrying earlier would be rejected again
+    let secs = delay.as_secs() + u64::from(delay.subsec_nanos() > 0);
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, HeaderValue::from(secs.max(1)));
+    response
+}
+
+/// Identify the client sending an event to a pipeline, for rate limiting.
+///
+/// Requests without a principal are identified by their IP address, events without the attribute
+/// share a single limit. Each pipeline has its own limits, like principals are scoped to their
+/// pipeline.
+fn client(rate: &RateLimiter, pipeline: &str, req: &HttpRequest, event: &Event) -> String {
+    let principal = || req.extensions().get::<Principal>().map(ToString::to_string);
+    let ip = || {
+        req.peer_addr()
+            .map(|addr| addr.ip().to_string())
+            .unwrap_or_default()
+    };
+    let scoped = |client: String| format!("{}/{}", pipeline, client);
+
+    match rate.key {
+        Key::Principal => principal().unwrap_or_else(|| scoped(ip())),
+        Key::Ip => scoped(ip()),
+        Key::Attribute => scoped(
+            event
+                .iter()
+                .find(|(name, _)| *name == rate.attribute.as_str())
+                .map(|(_, value)| value.to_string())
+                .unwrap_or_default(),
+        ),
+    }
+}
+
 pub async fn forward(
+    req: HttpRequest,
     event: Event,
     processor: web::Data<Processor>,
+    limiter: web::Data<Limiter>,
 ) -> Result<HttpResponse, actix_web::Error> {
     log::debug!("Received Event: {:?}", event);
 
-    Ok(match processor.process(event).await? {
-        0 => HttpResponse::NoContent().finish(),
-        _ => HttpResponse::Accepted().finish(),
+    if let Some(rate) = &limiter.rate {
+        let client = client(rate, &processor.name, &req, &event);
+        if let Err(delay) = rate.check(&client) {
+            log::debug!("Rate limited client '{}'", client);
+            metrics::RATE_LIMITED
+                .with_label_values(&[&processor.name])
+                .inc();
+            return Ok(retry_after(
+                HttpResponse::TooManyRequests().json(ErrorResponse {
+                    error: "RateLimited".into(),
+                    message: "Too many events from client".into(),
+                    fields: vec![],
+                }),
+                delay,
+            ));
+        }
+    }
+
+    let _permit = match &limiter.permits {
+        Some(permits) => match permits.try_acquire() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                return Ok(limiter.retry_later(HttpResponse::ServiceUnavailable().json(
+                    ErrorResponse {
+                        error: "Overloaded".into(),
+                        message: "Too many events in flight".into(),
+                        fields: vec![],
+                    },
+                )))
+            }
+        },
+        None => None,
+    };
+
+    Ok(match processor.process(event).await {
+        Ok(Outcome::NoValues | Outcome::Filtered) => HttpResponse::NoContent().finish(),
+        Ok(Outcome::Written | Outcome::Accepted | Outcome::Duplicate) => {
+            HttpResponse::Accepted().finish()
+        }
+        Err(err @ (ServiceError::Unavailable(_) | ServiceError::Overloaded(_))) => {
+            limiter.retry_later(err.error_response())
+        }
+        Err(err) => return Err(err.into()),
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        expected::ExpectedType, extract::Path, filter::Rule, pipeline, selector::Selector,
+    };
+    use actix_web::{http::StatusCode, test, App};
+    use serde_json::{json, Value};
+    use std::convert::TryFrom;
+
+    /// Create a processor, extracting the `temp` field, and optionally including events by a rule.
+    ///
+    /// Events are acknowledged once queued, so that events with values are accepted without a
+    /// database to write to.
+    fn processor(include: Option<&str>) -> web::Data<Processor> {
+        named_processor("test", include)
+    }
+
+    fn named_processor(name: &str, include: Option<&str>) -> web::Data<Processor> {
+        let config: pipeline::Config = serde_json::from_value(json!({
+            "postgresql": {
+                "table": "test",
+                "connection": {"host": "localhost", "port": 1, "dbname": "test"},
+            },
+            "acknowledge": "async",
+        }))
+        .unwrap();
+
+        let mut processor = Processor::new(name, "TEST_HTTP__", config).unwrap();
+        processor.fields.insert(
+            "temp".into(),
+            Path {
+                path: "$.temp".into(),
+                selector: Selector::try_from("$.temp").unwrap(),
+                r#type: ExpectedType::None,
+                policy: Default::default(),
+                transform: Default::default(),
+            },
+        );
+        if let Some(include) = include {
+            processor
+                .filter
+                .include
+                .push(Rule::try_from(include.to_string()).unwrap());
+        }
+
+        web::Data::new(processor)
+    }
+
+    async fn send(processor: &web::Data<Processor>, request: test::TestRequest) -> StatusCode {
+        let limiter = Limiter::new(&EndpointConfig::default()).unwrap();
+        send_limited(processor, web::Data::new(limiter), request)
+            .await
+            .status()
+    }
+
+    async fn send_limited(
+        processor: &web::Data<Processor>,
+        limiter: web::Data<Limiter>,
+        request: test::TestRequest,
+    ) -> actix_web::dev::ServiceResponse {
+        let app = test::init_service(
+            App::new()
+                .app_data(processor.clone())
+                .app_data(limiter)
+                .route("/", web::post().to(forward)),
+        )
+        .await;
+        test::call_service(&app, request.to_request()).await
+    }
+
+    fn binary(content_type: &str, payload: impl Into<Vec<u8>>) -> test::TestRequest {
+        test::TestRequest::post()
+            .insert_header(("ce-specversion", "1.0"))
+            .insert_header(("ce-id", "1"))
+            .insert_header(("ce-source", "test"))
+            .insert_header(("ce-type", "test"))
+            .insert_header(("content-type", content_type))
+            .set_payload(payload.into())
+    }
+
+    fn structured(data: Value) -> test::TestRequest {
+        let mut event = json!({
+            "specversion": "1.0",
+            "id": "1",
+            "source": "test",
+            "type": "test",
+        });
+        event
+            .as_object_mut()
+            .unwrap()
+            .extend(data.as_object().unwrap().clone());
+
+        test::TestRequest::post()
+            .insert_header(("content-type", "application/cloudevents+json"))
+            .set_payload(event.to_string())
+    }
+
+    fn cbor(value: Value) -> Vec<u8> {
+        let mut result = Vec::new();
+        ciborium::ser::into_writer(&value, &mut result).unwrap();
+        result
+    }
+
+    #[actix_web::test]
+    async fn test_binary_mode() {
+        let processor = processor(None);
+
+        let status = send(&processor, binary("application/json", r#"{"temp": 21.5}"#)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let status = send(
+            &processor,
+            binary("application/json", r#"{"humidity": 40}"#),
+        )
+        .await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let status = send(&processor, binary("text/plain", r#"{"temp": 21.5}"#)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let status = send(&processor, binary("text/plain", "not json")).await;
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+
+        let status = send(
+            &processor,
+            binary("application/cbor", cbor(json!({"temp": 21.5}))),
+        )
+        .await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        // not an event, without the required attributes
+        let request = test::TestRequest::post()
+            .insert_header(("ce-id", "1"))
+            .insert_header(("content-type", "application/json"))
+            .set_payload(r#"{"temp": 21.5}"#);
+        assert_eq!(send(&processor, request).await, StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_charset() {
+        let processor = processor(None);
+
+        let payload = &b"{\"temp\": 21.5, \"room\": \"K\xfcche\"}"[..];
+        let status = send(&processor, binary("text/plain", payload)).await;
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+
+        let content_type = "text/plain; charset=iso-8859-1";
+        let status = send(&processor, binary(content_type, payload)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let content_type = "text/plain; charset=koi8-r";
+        let status = send(&processor, binary(content_type, r#"{"temp": 21.5}"#)).await;
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+    }
+
+    #[actix_web::test]
+    async fn test_structured_mode() {
+        let processor = processor(None);
+
+        let event = json!({"datacontenttype": "application/json", "data": {"temp": 21.5}});
+        let status = send(&processor, structured(event)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let event = json!({"data": {"temp": 21.5}});
+        let status = send(&processor, structured(event)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let event = json!({"datacontenttype": "application/json", "data": {"humidity": 40}});
+        let status = send(&processor, structured(event)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+    }
+
+    #[actix_web::test]
+    async fn test_structured_mode_base64() {
+        let processor = processor(None);
+
+        let event = json!({
+            "datacontenttype": "application/json",
+            "data_base64": base64::encode(r#"{"temp": 21.5}"#),
+        });
+        let status = send(&processor, structured(event)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let event = json!({
+            "datacontenttype": "application/cbor",
+            "data_base64": base64::encode(cbor(json!({"temp": 21.5}))),
+        });
+        let status = send(&processor, structured(event)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        // a binary payload, carried as string, must be base64 encoded
+        let event = json!({
+            "datacontenttype": "application/cbor",
+            "data": base64::encode(cbor(json!({"temp": 21.5}))),
+        });
+        let status = send(&processor, structured(event)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let event = json!({"datacontenttype": "application/cbor", "data": "not base64"});
+        let status = send(&processor, structured(event)).await;
+        assert_eq!(status, StatusCode::NOT_ACCEPTABLE);
+    }
+
+    #[actix_web::test]
+    async fn test_extensions() {
+        let processor = processor(Some("device=device1"));
+
+        let request = binary("application/json", r#"{"temp": 21.5}"#);
+        let status = send(&processor, request.insert_header(("ce-device", "device1"))).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let request = binary("application/json", r#"{"temp": 21.5}"#);
+        let status = send(&processor, request.insert_header(("ce-device", "device2"))).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+
+        let event = json!({"device": "device1", "data": {"temp": 21.5}});
+        let status = send(&processor, structured(event)).await;
+        assert_eq!(status, StatusCode::ACCEPTED);
+
+        let event = json!({"device": "device2", "data": {"temp": 21.5}});
+        let status = send(&processor, structured(event)).await;
+        assert_eq!(status, StatusCode::NO_CONTENT);
+    }
+
+    #[actix_web::test]
+    async fn test_saturated() {
+        let processor = processor(None);
+        let limiter = web::Data::new(
+            Limiter::new(&EndpointConfig {
+                max_concurrent_events: Some(1),
+                retry_after: Duration::from_secs(5),
+                ..Default::default()
+            })
+            .unwrap(),
+        );
+
+        // an event still being processed
+        let permit = limiter.permits.as_ref().unwrap().try_acquire().unwrap();
+
+        let response = send_limited(
+            &processor,
+            limiter.clone(),
+            binary("application/json", r#"{"temp": 21.5}"#),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers().get(RETRY_AFTER),
+            Some(&HeaderValue::from(5u64))
+        );
+
+        drop(permit);
+        let response = send_limited(
+            &processor,
+            limiter,
+            binary("application/json", r#"{"temp": 21.5}"#),
+        )
+        .await;
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+    }
+
+    #[actix_web::test]
+    async fn test_no_concurrent_events() {
+        let config = EndpointConfig {
+            max_concurrent_events: Some(0),
+            ..Default::default()
+        };
+        assert!(Limiter::new(&config).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_rate_limited() {
+        let processor = processor(None);
+        let other = named_processor("other", None);
+        let limiter = Limiter::new(&EndpointConfig {
+            rate_limit: Some(ratelimit::Config {
+                key: Key::Attribute,
+                attribute: Some("device".into()),
+                rate: 0.5,
+                burst: Some(1),
+            }),
+            ..Default::default()
+        })
+        .unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(processor.clone())
+                .app_data(web::Data::new(limiter))
+                .route("/", web::post().to(forward))
+                .service(
+                    web::resource("/other")
+                        .app_data(other)
+                        .route(web::post().to(forward)),
+                ),
+        )
+        .await;
+
+        let request = |uri: &str, device: &str| {
+            binary("application/json", r#"{"humidity": 40}"#)
+                .uri(uri)
+                .insert_header(("ce-device", device.to_string()))
+                .to_request()
+        };
+
+        let response = test::call_service(&app, request("/", "device1")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        let response = test::call_service(&app, request("/", "device1")).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(
+            response.headers().get(RETRY_AFTER),
+            Some(&HeaderValue::from(2u64))
+        );
+
+        let response = test::call_service(&app, request("/", "device2")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+        // another pipeline limits the same device separately
+        let response = test::call_service(&app, request("/other", "device1")).await;
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    }
+}
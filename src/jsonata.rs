@@ -0,0 +1,832 @@
+use crate::error::ServiceError;
+use serde_json::{Number, Value};
+use std::convert::TryFrom;
+use std::fmt::{Display, Formatter};
+
+/// A JSONata-style expression, selecting a value from a JSON structure.
+///
+/// This implements the commonly used part of [JSONata](https://jsonata.org/): paths, which map
+/// over arrays (`readings.value`), predicates and indexes (`readings[type = 'temp'][0]`),
+/// arithmetic, comparison, string concatenation (`&`) and boolean operators, the conditional
+/// (`cond ? a : b`), and a fixed set of functions (`$sum(readings.value)`). There are no
+/// variable bindings, lambdas or regular expressions.
+#[derive(Debug, Clone)]
+pub enum Expression {
+    Literal(Value),
+    /// A field of the context value, mapped over arrays.
+    Name(String),
+    /// All fields of the context value (`*`).
+    Wildcard,
+    /// The context value (`$`).
+    Context,
+    /// The root value (`$$`).
+    Root,
+    /// A path, each step being evaluated against the values of the previous one.
+    Path(Vec<Expression>),
+    /// Filter the values of an expression by a predicate, or an index.
+    Predicate(Box<Expression>, Box<Expression>),
+    Negate(Box<Expression>),
+    Binary(BinaryOp, Box<Expression>, Box<Expression>),
+    Conditional(Box<Expression>, Box<Expression>, Option<Box<Expression>>),
+    Call(Function, Vec<Expression>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+    Concat,
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    And,
+    Or,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Function {
+    Sum,
+    Count,
+    Max,
+    Min,
+    Average,
+    Number,
+    String,
+    Round,
+    Abs,
+    Floor,
+    Ceil,
+    Lowercase,
+    Uppercase,
+    Join,
+    Exists,
+    Not,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "sum" => Self::Sum,
+            "count" => Self::Count,
+            "max" => Self::Max,
+            "min" => Self::Min,
+            "average" => Self::Average,
+            "number" => Self::Number,
+            "string" => Self::String,
+            "round" => Self::Round,
+            "abs" => Self::Abs,
+            "floor" => Self::Floor,
+            "ceil" => Self::Ceil,
+            "lowercase" => Self::Lowercase,
+            "uppercase" => Self::Uppercase,
+            "join" => Self::Join,
+            "exists" => Self::Exists,
+            "not" => Self::Not,
+            _ => return None,
+        })
+    }
+
+    /// The minimum and maximum number of arguments.
+    fn arity(&self) -> (usize, usize) {
+        match self {
+            Self::Round | Self::Join => (1, 2),
+            _ => (1, 1),
+        }
+    }
+}
+
+impl Display for Function {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "${}", format!("{:?}", self).to_lowercase())
+    }
+}
+
+/// The values of an evaluated expression: none (undefined), a single value, or a sequence.
+type Sequence = Vec<Value>;
+
+impl Expression {
+    /// Evaluate the expression, returning no value if the result is undefined, and a sequence of
+    /// multiple values as an array.
+    pub fn evaluate(&self, json: &Value) -> Result<Option<Value>, ServiceError> {
+        let mut result = self.eval(json, json)?;
+        Ok(match result.len() {
+            0 => None,
+            1 => result.pop(),
+            _ => Some(Value::Array(result)),
+        })
+    }
+
+    fn eval(&self, context: &Value, root: &Value) -> Result<Sequence, ServiceError> {
+        Ok(match self {
+            Self::Literal(value) => vec![value.clone()],
+            Self::Name(name) => {
+                let mut result = Vec::new();
+                field(context, name, &mut result);
+                result
+            }
+            Self::Wildcard => {
+                let mut result = Vec::new();
+                wildcard(context, &mut result);
+                result
+            }
+            Self::Context => vec![context.clone()],
+            Self::Root => vec![root.clone()],
+            Self::Path(steps) => {
+                let mut result = steps[0].eval(context, root)?;
+                for step in &steps[1..] {
+                    let mut next = Vec::new();
+                    for value in &result {
+                        next.extend(step.eval(value, root)?);
+                    }
+                    result = next;
+                }
+                result
+            }
+            Self::Predicate(e, predicate) => {
+                let values = flatten(e.eval(context, root)?);
+                let len = values.len() as i64;
+                let mut result = Vec::new();
+                for (i, value) in values.into_iter().enumerate() {
+                    let keep = match single(predicate.eval(&value, root)?)? {
+                        // a number selects by index, negative ones counting from the end
+                        Some(Value::Number(n)) => {
+                            let index = n.as_f64().unwrap_or_default().floor() as i64;
+                            i as i64 == if index < 0 { len + index } else { index }
+                        }
+                        other => truthy(other.as_ref()),
+                    };
+                    if keep {
+                        result.push(value);
+                    }
+                }
+                result
+            }
+            Self::Negate(e) => match single(e.eval(context, root)?)? {
+                None => vec![],
+                Some(value) => vec![to_value(-number(&value)?)?],
+            },
+            Self::Binary(op, l, r) => {
+                let l = single(l.eval(context, root)?)?;
+                let r = single(r.eval(context, root)?)?;
+                binary(*op, l, r)?.into_iter().collect()
+            }
+            Self::Conditional(c, a, b) => match truthy(single(c.eval(context, root)?)?.as_ref()) {
+                true => a.eval(context, root)?,
+                false => match b {
+                    Some(b) => b.eval(context, root)?,
+                    None => vec![],
+                },
+            },
+            Self::Call(f, args) => {
+                let args = args
+                    .iter()
+                    .map(|e| e.eval(context, root))
+                    .collect::<Result<Vec<_>, _>>()?;
+                call(*f, args)?.into_iter().collect()
+            }
+        })
+    }
+}
+
+fn error<T>(message: String) -> Result<T, ServiceError> {
+    Err(ServiceError::Selector(message))
+}
+
+/// Select a field, mapping over arrays.
+fn field(value: &Value, name: &str, result: &mut Sequence) {
+    match value {
+        Value::Object(map) => result.extend(map.get(name).cloned()),
+        Value::Array(items) => items.iter().for_each(|item| field(item, name, result)),
+        _ => {}
+    }
+}
+
+fn wildcard(value: &Value, result: &mut Sequence) {
+    match value {
+        Value::Object(map) => result.extend(map.values().cloned()),
+        Value::Array(items) => items.iter().for_each(|item| wildcard(item, result)),
+        _ => {}
+    }
+}
+
+/// Expand arrays into the sequence.
+fn flatten(values: Sequence) -> Sequence {
+    let mut result = Vec::with_capacity(values.len());
+    for value in values {
+        match value {
+            Value::Array(items) => result.extend(items),
+            value => result.push(value),
+        }
+    }
+    result
+}
+
+/// Expect an operand to be a single value, or undefined.
+fn single(mut values: Sequence) -> Result<Option<Value>, ServiceError> {
+    match values.len() {
+        0 | 1 => Ok(values.pop()),
+        n => error(format!("Expected a single value, found: {}", n)),
+    }
+}
+
+fn truthy(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) => false,
+        Some(Value::Bool(b)) => *b,
+        Some(Value::Number(n)) => n.as_f64().unwrap_or_default() != 0.0,
+        Some(Value::String(s)) => !s.is_empty(),
+        Some(Value::Array(items)) => items.iter().any(|item| truthy(Some(item))),
+        Some(Value::Object(map)) => !map.is_empty(),
+    }
+}
+
+fn number(value: &Value) -> Result<f64, ServiceError> {
+    match value.as_f64() {
+        Some(n) => Ok(n),
+        None => error(format!("Expected numeric value, found: {}", value)),
+    }
+}
+
+fn string(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        value => value.to_string(),
+    }
+}
+
+/// Convert a result to a number, an integer if it has no fraction.
+fn to_value(n: f64) -> Result<Value, ServiceError> {
+    if n.fract() == 0.0 && n.abs() < i64::MAX as f64 {
+        return Ok(Value::from(n as i64));
+    }
+    Number::from_f64(n)
+        .map(Value::Number)
+        .ok_or_else(|| ServiceError::Selector(format!("Result is not a finite number: {}", n)))
+}
+
+fn equal(l: &Value, r: &Value) -> bool {
+    match (l.as_f64(), r.as_f64()) {
+        (Some(l), Some(r)) => l == r,
+        _ => l == r,
+    }
+}
+
+fn compare(l: &Value, r: &Value) -> Result<std::cmp::Ordering, ServiceError> {
+    let ordering = match (l, r) {
+        (Value::String(l), Value::String(r)) => Some(l.cmp(r)),
+        (Value::Number(_), Value::Number(_)) => number(l)?.partial_cmp(&number(r)?),
+        _ => None,
+    };
+    ordering.ok_or_else(|| ServiceError::Selector(format!("Can't compare {} with {}", l, r)))
+}
+
+fn binary(op: BinaryOp, l: Option<Value>, r: Option<Value>) -> Result<Option<Value>, ServiceError> {
+    let (l, r) = match (op, l, r) {
+        (BinaryOp::And, l, r) => {
+            return Ok(Some(Value::Bool(truthy(l.as_ref()) && truthy(r.as_ref()))))
+        }
+        (BinaryOp::Or, l, r) => {
+            return Ok(Some(Value::Bool(truthy(l.as_ref()) || truthy(r.as_ref()))))
+        }
+        (BinaryOp::Concat, l, r) => {
+            let (l, r) = (l.as_ref().map(string), r.as_ref().map(string));
+            return Ok(Some(Value::String(
+                l.unwrap_or_default() + &r.unwrap_or_default(),
+            )));
+        }
+        (BinaryOp::Equal, Some(l), Some(r)) => return Ok(Some(Value::Bool(equal(&l, &r)))),
+        (BinaryOp::NotEqual, Some(l), Some(r)) => return Ok(Some(Value::Bool(!equal(&l, &r)))),
+        // comparing with an undefined operand is false, arithmetic is undefined
+        (op, None, _) | (op, _, None) => {
+            return Ok(match op {
+                BinaryOp::Equal
+                | BinaryOp::NotEqual
+                | BinaryOp::Less
+                | BinaryOp::LessOrEqual
+                | BinaryOp::Greater
+                | BinaryOp::GreaterOrEqual => Some(Value::Bool(false)),
+                _ => None,
+            })
+        }
+        (_, Some(l), Some(r)) => (l, r),
+    };
+
+    let ordering = |expected: &[std::cmp::Ordering]| -> Result<Option<Value>, ServiceError> {
+        Ok(Some(Value::Bool(expected.contains(&compare(&l, &r)?))))
+    };
+    use std::cmp::Ordering::*;
+    match op {
+        BinaryOp::Less => return ordering(&[Less]),
+        BinaryOp::LessOrEqual => return ordering(&[Less, Equal]),
+        BinaryOp::Greater => return ordering(&[Greater]),
+        BinaryOp::GreaterOrEqual => return ordering(&[Greater, Equal]),
+        _ => {}
+    }
+
+    let (a, b) = (number(&l)?, number(&r)?);
+    let result = match op {
+        BinaryOp::Add => a + b,
+        BinaryOp::Subtract => a - b,
+        BinaryOp::Multiply => a * b,
+        BinaryOp::Divide if b == 0.0 => return error("Division by zero".into()),
+        BinaryOp::Divide => a / b,
+        BinaryOp::Remainder if b == 0.0 => return error("Division by zero".into()),
+        BinaryOp::Remainder => a % b,
+        _ => unreachable!(),
+    };
+    to_value(result).map(Some)
+}
+
+fn call(f: Function, mut args: Vec<Sequence>) -> Result<Option<Value>, ServiceError> {
+    let values = flatten(args.remove(0));
+
+    match f {
+        Function::Count => return Ok(Some(Value::from(values.len()))),
+        Function::Exists => return Ok(Some(Value::Bool(!values.is_empty()))),
+        Function::Sum => {
+            let sum = values.iter().map(number).sum::<Result<f64, _>>()?;
+            return to_value(sum).map(Some);
+        }
+        Function::Join => {
+            let separator = match args.pop().map(single).transpose()?.flatten() {
+                Some(Value::String(separator)) => separator,
+                Some(value) => return error(format!("Expected separator, found: {}", value)),
+                None => String::new(),
+            };
+            let strings = values
+                .iter()
+                .map(|value| match value {
+                    Value::String(s) => Ok(s.as_str()),
+                    value => error(format!("Expected string value, found: {}", value)),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            return Ok(Some(Value::String(strings.join(&separator))));
+        }
+        Function::Max | Function::Min | Function::Average => {
+            if values.is_empty() {
+                return Ok(None);
+            }
+            let numbers = values.iter().map(number).collect::<Result<Vec<_>, _>>()?;
+            let result = match f {
+                Function::Max => numbers.iter().cloned().fold(f64::MIN, f64::max),
+                Function::Min => numbers.iter().cloned().fold(f64::MAX, f64::min),
+                _ => numbers.iter().sum::<f64>() / numbers.len() as f64,
+            };
+            return to_value(result).map(Some);
+        }
+        _ => {}
+    }
+
+    // all other functions take a single value, and are undefined for an undefined argument
+    let value = match single(values)? {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+
+    Ok(Some(match f {
+        Function::Number => match &value {
+            Value::Number(_) => value,
+            Value::String(s) => match s.trim().parse::<f64>() {
+                Ok(n) => to_value(n)?,
+                Err(_) => return error(format!("Can't convert to number: {}", s)),
+            },
+            Value::Bool(b) => Value::from(i64::from(*b)),
+            value => return error(format!("Can't convert to number: {}", value)),
+        },
+        Function::String => Value::String(string(&value)),
+        Function::Round => {
+            let digits = match args.pop().map(single).transpose()?.flatten() {
+                Some(digits) => number(&digits)? as i32,
+                None => 0,
+            };
+            let factor = 10f64.powi(digits);
+            // like JSONata, round half to even
+            to_value((number(&value)? * factor).round_ties_even() / factor)?
+        }
+        Function::Abs => to_value(number(&value)?.abs())?,
+        Function::Floor => to_value(number(&value)?.floor())?,
+        Function::Ceil => to_value(number(&value)?.ceil())?,
+        Function::Lowercase => Value::String(string(&value).to_lowercase()),
+        Function::Uppercase => Value::String(string(&value).to_uppercase()),
+        Function::Not => Value::Bool(!truthy(Some(&value))),
+        Function::Count
+        | Function::Exists
+        | Function::Sum
+        | Function::Join
+        | Function::Max
+        | Function::Min
+        | Function::Average => unreachable!(),
+    }))
+}
+
+// parsing
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    String(String),
+    Name(String),
+    /// A name in backticks, never a keyword.
+    Quoted(String),
+    /// `$`, `$$` or a function name, including the `$`.
+    Variable(String),
+    Operator(&'static str),
+}
+
+const OPERATORS: &[&str] = &[
+    "!=", "<=", ">=", "=", "<", ">", "+", "-", "*", "/", "%", "&", ".", "[", "]", "(", ")", ",",
+    "?", ":",
+];
+
+fn tokenize(input: &str) -> anyhow::Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let chars: Vec<char> = input.chars().collect();
+    let mut i = 0;
+
+    // read until the closing quote, handling escapes
+    let quoted = |i: &mut usize, quote: char| -> anyhow::Result<String> {
+        let mut s = String::new();
+        *i += 1;
+        loop {
+            match chars.get(*i) {
+                None => anyhow::bail!("Unterminated string literal"),
+                Some('\\') if quote != '`' => {
+                    s.extend(chars.get(*i + 1));
+                    *i += 2;
+                }
+                Some(q) if *q == quote => {
+                    *i += 1;
+                    return Ok(s);
+                }
+                Some(x) => {
+                    s.push(*x);
+                    *i += 1;
+                }
+            }
+        }
+    };
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            c if c.is_whitespace() => i += 1,
+            '"' | '\'' => tokens.push(Token::String(quoted(&mut i, c)?)),
+            '`' => tokens.push(Token::Quoted(quoted(&mut i, c)?)),
+            c if c.is_ascii_digit() => {
+                let start = i;
+                while i < chars.len() && chars[i].is_ascii_digit() {
+                    i += 1;
+                }
+                // a dot is only part of the number, if followed by a digit
+                if chars.get(i) == Some(&'.')
+                    && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit())
+                {
+                    i += 1;
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+                if matches!(chars.get(i), Some('e' | 'E')) {
+                    i += 1;
+                    if matches!(chars.get(i), Some('+' | '-')) {
+                        i += 1;
+                    }
+                    while i < chars.len() && chars[i].is_ascii_digit() {
+                        i += 1;
+                    }
+                }
+                let s: String = chars[start..i].iter().collect();
+                tokens.push(Token::Number(
+                    s.parse()
+                        .map_err(|_| anyhow::anyhow!("Invalid number: {}", s))?,
+                ));
+            }
+            '$' => {
+                let start = i;
+                i += 1;
+                if chars.get(i) == Some(&'$') {
+                    i += 1;
+                } else {
+                    while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                        i += 1;
+                    }
+                }
+                tokens.push(Token::Variable(chars[start..i].iter().collect()));
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                tokens.push(Token::Name(chars[start..i].iter().collect()));
+            }
+            _ => {
+                let rest: String = chars[i..].iter().take(2).collect();
+                let op = OPERATORS
+                    .iter()
+                    .find(|op| rest.starts_with(*op))
+                    .ok_or_else(|| anyhow::anyhow!("Unexpected character: {}", c))?;
+                tokens.push(Token::Operator(op));
+                i += op.len();
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn next_if(&mut self, op: &str) -> bool {
+        match self.peek() {
+            Some(Token::Operator(o)) if *o == op => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn expect(&mut self, op: &str) -> anyhow::Result<()> {
+        match self.next_if(op) {
+            true => Ok(()),
+            false => anyhow::bail!("Expected '{}', found: {:?}", op, self.peek()),
+        }
+    }
+
+    fn conditional(&mut self) -> anyhow::Result<Expression> {
+        let condition = self.binary(0)?;
+        if self.next_if("?") {
+            let a = self.conditional()?;
+            let b = match self.next_if(":") {
+                true => Some(Box::new(self.conditional()?)),
+                false => None,
+            };
+            return Ok(Expression::Conditional(Box::new(condition), Box::new(a), b));
+        }
+        Ok(condition)
+    }
+
+    /// Precedence climbing over the binary operators.
+    fn binary(&mut self, min_precedence: u8) -> anyhow::Result<Expression> {
+        let mut left = self.unary()?;
+
+        loop {
+            let (op, precedence) = match self.peek() {
+                Some(Token::Name(name)) if name == "or" => (BinaryOp::Or, 0),
+                Some(Token::Name(name)) if name == "and" => (BinaryOp::And, 1),
+                Some(Token::Operator(op)) => match *op {
+                    "=" => (BinaryOp::Equal, 2),
+                    "!=" => (BinaryOp::NotEqual, 2),
+                    "<" => (BinaryOp::Less, 2),
+                    "<=" => (BinaryOp::LessOrEqual, 2),
+                    ">" => (BinaryOp::Greater, 2),
+                    ">=" => (BinaryOp::GreaterOrEqual, 2),
+                    "&" => (BinaryOp::Concat, 3),
+                    "+" => (BinaryOp::Add, 4),
+                    "-" => (BinaryOp::Subtract, 4),
+                    "*" => (BinaryOp::Multiply, 5),
+                    "/" => (BinaryOp::Divide, 5),
+                    "%" => (BinaryOp::Remainder, 5),
+                    _ => break,
+                },
+                _ => break,
+            };
+            if precedence < min_precedence {
+                break;
+            }
+            self.next();
+            let right = self.binary(precedence + 1)?;
+            left = Expression::Binary(op, Box::new(left), Box::new(right));
+        }
+
+        Ok(left)
+    }
+
+    fn unary(&mut self) -> anyhow::Result<Expression> {
+        match self.next_if("-") {
+            true => Ok(Expression::Negate(Box::new(self.unary()?))),
+            false => self.path(),
+        }
+    }
+
+    fn path(&mut self) -> anyhow::Result<Expression> {
+        let mut steps = vec![self.step()?];
+        while self.next_if(".") {
+            steps.push(self.step()?);
+        }
+        Ok(match steps.len() {
+            1 => steps.remove(0),
+            _ => Expression::Path(steps),
+        })
+    }
+
+    fn step(&mut self) -> anyhow::Result<Expression> {
+        let mut step = self.primary()?;
+        while self.next_if("[") {
+            let predicate = self.conditional()?;
+            self.expect("]")?;
+            step = Expression::Predicate(Box::new(step), Box::new(predicate));
+        }
+        Ok(step)
+    }
+
+    fn primary(&mut self) -> anyhow::Result<Expression> {
+        match self.next() {
+            Some(Token::Number(n)) => Ok(Expression::Literal(
+                to_value(n).map_err(|err| anyhow::anyhow!("{}", err))?,
+            )),
+            Some(Token::String(s)) => Ok(Expression::Literal(Value::String(s))),
+            Some(Token::Quoted(name)) => Ok(Expression::Name(name)),
+            Some(Token::Name(name)) => Ok(match name.as_str() {
+                "true" => Expression::Literal(Value::Bool(true)),
+                "false" => Expression::Literal(Value::Bool(false)),
+                "null" => Expression::Literal(Value::Null),
+                _ => Expression::Name(name),
+            }),
+            Some(Token::Variable(name)) => match name.as_str() {
+                "$" => Ok(Expression::Context),
+                "$$" => Ok(Expression::Root),
+                name => self.call(&name[1..]),
+            },
+            Some(Token::Operator("*")) => Ok(Expression::Wildcard),
+            Some(Token::Operator("(")) => {
+                let e = self.conditional()?;
+                self.expect(")")?;
+                Ok(e)
+            }
+            token => anyhow::bail!("Unexpected token: {:?}", token),
+        }
+    }
+
+    fn call(&mut self, name: &str) -> anyhow::Result<Expression> {
+        let f = Function::from_name(name)
+            .ok_or_else(|| anyhow::anyhow!("Unknown function: ${}", name))?;
+
+        self.expect("(")?;
+        let mut args = Vec::new();
+        if !self.next_if(")") {
+            loop {
+                args.push(self.conditional()?);
+                if !self.next_if(",") {
+                    break;
+                }
+            }
+            self.expect(")")?;
+        }
+
+        let (min, max) = f.arity();
+        if args.len() < min || args.len() > max {
+            anyhow::bail!("Invalid number of arguments for '{}': {}", f, args.len());
+        }
+
+        Ok(Expression::Call(f, args))
+    }
+}
+
+impl TryFrom<&str> for Expression {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let mut parser = Parser {
+            tokens: tokenize(value)?,
+            pos: 0,
+        };
+        let expression = parser.conditional()?;
+        if let Some(token) = parser.peek() {
+            anyhow::bail!("Unexpected token: {:?}", token);
+        }
+        Ok(expression)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn eval(expression: &str) -> Option<Value> {
+        let json = json!({
+            "device": "d1",
+            "readings": [
+                {"type": "temp", "value": 21.5},
+                {"type": "hum", "value": 40},
+                {"type": "temp", "value": 22.5},
+            ],
+            "location": {"lat": 1.5, "lon": 2.5},
+            "sensor name": "s1",
+        });
+        Expression::try_from(expression)
+            .unwrap()
+            .evaluate(&json)
+            .unwrap()
+    }
+
+    #[test]
+    fn test_paths() {
+        assert_eq!(eval("device"), Some(json!("d1")));
+        assert_eq!(eval("location.lat"), Some(json!(1.5)));
+        assert_eq!(eval("readings.value"), Some(json!([21.5, 40, 22.5])));
+        assert_eq!(eval("readings[1].value"), Some(json!(40)));
+        assert_eq!(eval("readings[-1].value"), Some(json!(22.5)));
+        assert_eq!(
+            eval("readings[type = 'temp'].value"),
+            Some(json!([21.5, 22.5]))
+        );
+        assert_eq!(eval("readings[type = 'temp'][0].value"), Some(json!(21.5)));
+        assert_eq!(eval("location.*"), Some(json!([1.5, 2.5])));
+        assert_eq!(eval("`sensor name`"), Some(json!("s1")));
+        assert_eq!(eval("$.device"), Some(json!("d1")));
+        assert_eq!(eval("missing"), None);
+        assert_eq!(eval("readings[type = 'missing'].value"), None);
+    }
+
+    #[test]
+    fn test_operators() {
+        assert_eq!(eval("location.lat + location.lon * 2"), Some(json!(6.5)));
+        assert_eq!(eval("-(1 + 2) % 2"), Some(json!(-1)));
+        assert_eq!(eval("7 / 2"), Some(json!(3.5)));
+        assert_eq!(
+            eval("device & '/' & readings[0].type"),
+            Some(json!("d1/temp"))
+        );
+        assert_eq!(
+            eval("readings[0].value > 20 and device = 'd1'"),
+            Some(json!(true))
+        );
+        assert_eq!(eval("readings[1].value = 40.0"), Some(json!(true)));
+        assert_eq!(eval("missing = 1 or missing != 1"), Some(json!(false)));
+        assert_eq!(eval("missing + 1"), None);
+        assert_eq!(
+            eval("readings[0].value > 25 ? 'hot' : 'cold'"),
+            Some(json!("cold"))
+        );
+        assert_eq!(eval("readings[0].value > 25 ? 'hot'"), None);
+    }
+
+    #[test]
+    fn test_functions() {
+        assert_eq!(eval("$sum(readings.value)"), Some(json!(84)));
+        assert_eq!(eval("$count(readings)"), Some(json!(3)));
+        assert_eq!(
+            eval("$max(readings[type = 'temp'].value)"),
+            Some(json!(22.5))
+        );
+        assert_eq!(
+            eval("$average(readings[type = 'temp'].value)"),
+            Some(json!(22))
+        );
+        assert_eq!(eval("$round(1.25, 1)"), Some(json!(1.2)));
+        assert_eq!(eval("$round(2.567, 2)"), Some(json!(2.57)));
+        assert_eq!(eval("$round(2.5)"), Some(json!(2)));
+        assert_eq!(eval("$number('1.5') + 1"), Some(json!(2.5)));
+        assert_eq!(eval("$uppercase(device)"), Some(json!("D1")));
+        assert_eq!(
+            eval("$join(readings.type, ',')"),
+            Some(json!("temp,hum,temp"))
+        );
+        assert_eq!(eval("$exists(missing)"), Some(json!(false)));
+        assert_eq!(eval("$min(missing)"), None);
+    }
+
+    #[test]
+    fn test_errors() {
+        let json = json!({"a": [1, 2], "s": "x"});
+        let evaluate = |e: &str| Expression::try_from(e).unwrap().evaluate(&json);
+
+        assert!(evaluate("a + 1").is_err());
+        assert!(evaluate("s * 2").is_err());
+        assert!(evaluate("1 / 0").is_err());
+        assert!(evaluate("s < 1").is_err());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Expression::try_from("a.").is_err());
+        assert!(Expression::try_from("a[0").is_err());
+        assert!(Expression::try_from("$eval('1')").is_err());
+        assert!(Expression::try_from("$sum()").is_err());
+        assert!(Expression::try_from("'open").is_err());
+        assert!(Expression::try_from("a b").is_err());
+    }
+}
@@ -1,95 +1,349 @@
 mod config;
+mod dedup;
+mod discovery;
 mod error;
 mod expected;
+mod expression;
 mod extract;
+mod filter;
 mod http;
+mod jsonata;
+mod metrics;
+mod payload;
+mod pipeline;
+mod queue;
+mod ratelimit;
+mod replay;
+mod selector;
+mod senml;
+mod shutdown;
+mod source;
+mod tls;
+mod transform;
 mod writer;
 
 use crate::{
-    config::ConfigFromEnv, extract::Processor, http::EndpointConfig, writer::PostgresWriter,
+    config::ConfigFromEnv,
+    http::{AuthConfig, EndpointConfig, Principal},
+    pipeline::Pipeline,
+    tls::{ClientCertificate, TlsConfig},
+};
+use actix_web::{
+    dev::{HttpServiceFactory, Service, ServiceRequest},
+    middleware, web, App, Error, HttpMessage, HttpServer,
 };
-use actix_web::{dev::ServiceRequest, middleware, web, App, Error, HttpServer};
 use actix_web_httpauth::{
     extractors::{basic::BasicAuth, bearer::BearerAuth, AuthenticationError},
     headers::www_authenticate::basic::Basic,
     middleware::HttpAuthentication,
 };
+use clap::Parser;
+use futures::future::{self, Either};
 use serde::Deserialize;
 use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[derive(Clone, Debug, Deserialize)]
 struct Config {
     #[serde(default)]
     pub endpoint: EndpointConfig,
-    pub postgresql: writer::Config,
-    #[serde(default)]
-    pub disable_try_parse: bool,
+    /// Comma separated names of pipelines, if not set, a single default pipeline is used.
+    pub pipelines: Option<String>,
+    #[serde(default = "default_shutdown_grace_period", with = "humantime_serde")]
+    pub shutdown_grace_period: Duration,
 }
 
-static EMPTY: Cow<'static, str> = Cow::Borrowed("");
+#[inline]
+fn default_shutdown_grace_period() -> Duration {
+    Duration::from_secs(30)
+}
 
-async fn basic_auth(req: ServiceRequest, auth: BasicAuth) -> Result<ServiceRequest, Error> {
-    let config = req.app_data::<EndpointConfig>();
+#[derive(Debug, Parser)]
+#[clap(version, about)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Replay events from files or stdin, instead of running the server.
+    Replay(replay::Args),
+}
 
-    match config {
-        Some(EndpointConfig {
+static EMPTY: Cow<'static, str> = Cow::Borrowed("");
+
+async fn basic_auth(
+    req: ServiceRequest,
+    auth: BasicAuth,
+    pipeline: Arc<String>,
+    config: Arc<AuthConfig>,
+) -> Result<ServiceRequest, Error> {
+    match config.as_ref() {
+        AuthConfig {
             username: Some(username),
             password: Some(password),
             ..
-        }) if username == auth.user_id()
-            && password == auth.password().as_deref().unwrap_or(&EMPTY) =>
-        {
+        } if username == auth.user_id() && password == auth.password().unwrap_or(&EMPTY) => {
+            req.extensions_mut()
+                .insert(Principal::new(&pipeline, auth.user_id().to_string()));
             Ok(req)
         }
         _ => Err(AuthenticationError::new(Basic::new()).into()),
     }
 }
 
-async fn bearer_auth(req: ServiceRequest, auth: BearerAuth) -> Result<ServiceRequest, Error> {
-    let config = req.app_data::<EndpointConfig>();
-
-    match config {
-        Some(EndpointConfig {
+async fn bearer_auth(
+    req: ServiceRequest,
+    auth: BearerAuth,
+    pipeline: Arc<String>,
+    config: Arc<AuthConfig>,
+) -> Result<ServiceRequest, Error> {
+    match config.as_ref() {
+        AuthConfig {
             token: Some(token), ..
-        }) if token == auth.token() => Ok(req),
+        } if token == auth.token() => {
+            // the token is shared by all callers of the pipeline, and so is the principal
+            req.extensions_mut()
+                .insert(Principal::new(&pipeline, "bearer"));
+            Ok(req)
+        }
         _ => Err(AuthenticationError::new(Basic::new()).into()),
     }
 }
 
+/// Authenticate the client by its certificate, if one of its names is allowed for the pipeline.
+fn client_auth(req: &ServiceRequest, pipeline: &str, clients: &[String]) -> Result<(), Error> {
+    let certificate = req
+        .request()
+        .conn_data::<ClientCertificate>()
+        .ok_or_else(|| actix_web::error::ErrorUnauthorized("Missing client certificate"))?;
+
+    let name = certificate
+        .names
+        .iter()
+        .find(|name| clients.contains(name))
+        .ok_or_else(|| actix_web::error::ErrorForbidden("Client certificate not allowed"))?;
+
+    req.extensions_mut()
+        .insert(Principal::new(pipeline, name.clone()));
+    Ok(())
+}
+
+/// Create the resource receiving events for a pipeline, including its authentication.
+fn pipeline_resource(pipeline: &Pipeline) -> impl HttpServiceFactory {
+    let has_basic = pipeline.auth.username.is_some();
+    let has_bearer = pipeline.auth.token.is_some();
+    let clients = Arc::new(pipeline.auth.clients());
+
+    let name = Arc::new(pipeline.name.clone());
+    let (basic_name, bearer_name) = (name.clone(), name.clone());
+    let basic = Arc::new(pipeline.auth.clone());
+    let bearer = basic.clone();
+
+    web::resource(pipeline.path.as_str())
+        .app_data(pipeline.processor.clone())
+        .route(web::post().to(http::forward))
+        .wrap(middleware::Condition::new(
+            has_basic,
+            HttpAuthentication::basic(move |req, auth| {
+                basic_auth(req, auth, basic_name.clone(), basic.clone())
+            }),
+        ))
+        .wrap(middleware::Condition::new(
+            has_bearer,
+            HttpAuthentication::bearer(move |req, auth| {
+                bearer_auth(req, auth, bearer_name.clone(), bearer.clone())
+            }),
+        ))
+        .wrap_fn(move |req, srv| {
+            let result = if clients.is_empty() {
+                Ok(())
+            } else {
+                client_auth(&req, &name, &clients)
+            };
+            match result {
+                Ok(()) => Either::Left(srv.call(req)),
+                Err(err) => Either::Right(future::ready(Err(err))),
+            }
+        })
+}
+
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
     env_logger::init();
 
+    match Cli::parse().command {
+        Some(Command::Replay(args)) => replay::run(args).await,
+        None => serve().await,
+    }
+}
+
+async fn serve() -> anyhow::Result<()> {
     let config = Config::from_env()?;
-    let writer = PostgresWriter::new(config.postgresql)?;
+    let pipelines = Arc::new(pipeline::from_env(config.pipelines.as_deref())?);
 
-    let processor = web::Data::new(Processor::new(writer, config.disable_try_parse)?);
+    let (stop, stop_receiver) = tokio::sync::watch::channel(false);
+    let sources = source::start(&pipelines, stop_receiver)?;
 
     let max_json_payload_size = config.endpoint.max_json_payload_size;
+    let grace_period = config.shutdown_grace_period;
+
+    let limiter = web::Data::new(http::Limiter::new(&config.endpoint)?);
 
-    let has_basic = config.endpoint.username.is_some();
-    let has_bearer = config.endpoint.token.is_some();
-    let actix_config = config.endpoint.clone();
-
-    HttpServer::new(move || {
-        App::new()
-            .wrap(middleware::Condition::new(
-                has_basic,
-                HttpAuthentication::basic(basic_auth),
-            ))
-            .wrap(middleware::Condition::new(
-                has_bearer,
-                HttpAuthentication::bearer(bearer_auth),
-            ))
+    let has_client_ca = matches!(
+        &config.endpoint.tls,
+        Some(TlsConfig {
+            client_ca: Some(_),
+            ..
+        })
+    );
+    if let Some(pipeline) = pipelines
+        .iter()
+        .find(|pipeline| !has_client_ca && !pipeline.auth.clients().is_empty())
+    {
+        anyhow::bail!(
+            "Pipeline '{}' requires client certificates, but no client CA is configured",
+            pipeline.name
+        );
+    }
+
+    let server_pipelines = pipelines.clone();
+    let server = HttpServer::new(move || {
+        let mut app = App::new()
             .wrap(middleware::Logger::default())
-            .app_data(actix_config.clone())
             .app_data(web::JsonConfig::default().limit(max_json_payload_size))
-            .app_data(processor.clone())
-            .service(http::forward)
+            .app_data(limiter.clone())
+            .service(metrics::metrics);
+        for pipeline in server_pipelines.iter() {
+            app = app.service(pipeline_resource(pipeline));
+        }
+        app
     })
-    .bind(config.endpoint.bind_addr)?
-    .run()
-    .await?;
+    .on_connect(tls::on_connect);
+
+    let server = match &config.endpoint.tls {
+        Some(tls) => {
+            log::info!("Serving HTTPS on {}", config.endpoint.bind_addr);
+            server.bind_openssl(&config.endpoint.bind_addr, tls::acceptor(tls)?)?
+        }
+        None => server.bind(&config.endpoint.bind_addr)?,
+    }
+    .shutdown_timeout(grace_period.as_secs())
+    .disable_signals()
+    .run();
+
+    let handle = server.handle();
+    let draining = pipelines.clone();
+    let deadline = actix_web::rt::spawn(async move {
+        shutdown::signal().await;
+
+        log::info!("Shutting down (grace period: {:?})", grace_period);
+        let deadline = Instant::now() + grace_period;
+
+        // count the writes completing from now on, or dropped once the grace period is over
+        pipeline::start_draining(&draining);
+
+        // stop receiving and accepting events, and wait for the HTTP server to finish in-flight
+        // requests, which still get written
+        let _ = stop.send(true);
+        handle.stop(true).await;
+
+        deadline
+    });
+
+    server.await?;
+
+    let deadline = deadline.await?;
+    source::shutdown(sources, deadline).await;
+
+    // nothing is being received anymore, reject late writes and drain the pending ones
+    pipeline::close(&pipelines);
+    pipeline::shutdown(&pipelines, deadline).await;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::{http::StatusCode, HttpRequest};
+    use std::io::{Read, Write};
+    use std::net::TcpStream;
+
+    /// Call a pipeline, authenticating clients by their certificate, over a connection with the
+    /// certificate, returning the status and the body.
+    async fn call(certificate: Option<ClientCertificate>) -> (StatusCode, String) {
+        let server = HttpServer::new(|| {
+            App::new().service(
+                web::resource("/")
+                    .wrap_fn(
+                        |req, srv| match client_auth(&req, "test", &["device1".to_string()]) {
+                            Ok(()) => Either::Left(srv.call(req)),
+                            Err(err) => Either::Right(future::ready(Err(err))),
+                        },
+                    )
+                    .to(|req: HttpRequest| async move {
+                        req.extensions()
+                            .get::<Principal>()
+                            .map(ToString::to_string)
+                            .unwrap_or_default()
+                    }),
+            )
+        })
+        .on_connect(move |_, data| {
+            if let Some(certificate) = &certificate {
+                data.insert(certificate.clone());
+            }
+        })
+        .workers(1)
+        .disable_signals()
+        .bind("127.0.0.1:0")
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let response = web::block(move || {
+            let mut stream = TcpStream::connect(addr)?;
+            stream.write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+            let mut response = String::new();
+            stream.read_to_string(&mut response)?;
+            Ok::<_, std::io::Error>(response)
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        handle.stop(false).await;
+
+        let status = response
+            .split(' ')
+            .nth(1)
+            .and_then(|status| status.parse::<u16>().ok())
+            .and_then(|status| StatusCode::from_u16(status).ok())
+            .unwrap();
+        let body = response.split("\r\n\r\n").nth(1).unwrap_or_default();
+        (status, body.to_string())
+    }
+
+    fn certificate(names: &[&str]) -> Option<ClientCertificate> {
+        Some(ClientCertificate {
+            names: names.iter().map(|name| name.to_string()).collect(),
+        })
+    }
+
+    #[actix_web::test]
+    async fn test_client_auth() {
+        assert_eq!(call(None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(
+            call(certificate(&["device2"])).await.0,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            call(certificate(&["device1.example.com", "device1"])).await,
+            (StatusCode::OK, "test/device1".to_string())
+        );
+    }
+}
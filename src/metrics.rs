@@ -0,0 +1,58 @@
+use actix_web::{get, HttpResponse};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_int_counter_vec, register_int_gauge_vec, Encoder, IntCounterVec, IntGaugeVec,
+    TextEncoder,
+};
+
+lazy_static! {
+    pub static ref EVENTS: IntCounterVec = register_int_counter_vec!(
+        "drogue_pusher_events",
+        "Events processed, by pipeline and outcome",
+        &["pipeline", "outcome"]
+    )
+    .unwrap();
+    pub static ref FIELDS_SKIPPED: IntCounterVec = register_int_counter_vec!(
+        "drogue_pusher_fields_skipped",
+        "Fields or tags which failed processing, and were skipped by policy, by pipeline and policy",
+        &["pipeline", "policy"]
+    )
+    .unwrap();
+    pub static ref TARGET_WRITES: IntCounterVec = register_int_counter_vec!(
+        "drogue_pusher_target_writes",
+        "Writes to database targets, by pipeline, target and outcome",
+        &["pipeline", "target", "outcome"]
+    )
+    .unwrap();
+    pub static ref QUEUED_EVENTS: IntGaugeVec = register_int_gauge_vec!(
+        "drogue_pusher_queued_events",
+        "Events waiting to be written, by pipeline",
+        &["pipeline"]
+    )
+    .unwrap();
+    pub static ref QUEUED_WRITES: IntCounterVec = register_int_counter_vec!(
+        "drogue_pusher_queued_writes",
+        "Writes of queued events, by pipeline and outcome",
+        &["pipeline", "outcome"]
+    )
+    .unwrap();
+    pub static ref RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+        "drogue_pusher_rate_limited",
+        "Events rejected because their client exceeded its rate limit, by pipeline",
+        &["pipeline"]
+    )
+    .unwrap();
+}
+
+#[get("/metrics")]
+pub async fn metrics() -> HttpResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = vec![];
+
+    match encoder.encode(&prometheus::gather(), &mut buffer) {
+        Ok(_) => HttpResponse::Ok()
+            .content_type(encoder.format_type())
+            .body(buffer),
+        Err(err) => HttpResponse::InternalServerError().body(err.to_string()),
+    }
+}
@@ -0,0 +1,348 @@
+use crate::error::ServiceError;
+use prost_reflect::{DescriptorPool, DynamicMessage, MessageDescriptor, SerializeOptions};
+use serde::Deserialize;
+use serde_json::{Map, Number, Value};
+use std::convert::TryFrom;
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct ProtobufConfig {
+    /// Path to a file containing a `FileDescriptorSet`.
+    pub descriptor_set: Option<String>,
+    /// The full name of the message to decode, unless chosen by the `dataschema` attribute.
+    pub message: Option<String>,
+}
+
+/// Decoder for protobuf payloads, based on a descriptor set.
+pub struct Protobuf {
+    pool: DescriptorPool,
+    default_message: Option<MessageDescriptor>,
+}
+
+impl Protobuf {
+    /// Create a new decoder, or `None` if no descriptor set is configured.
+    pub fn new(config: ProtobufConfig) -> anyhow::Result<Option<Self>> {
+        let descriptor_set = match config.descriptor_set {
+            Some(descriptor_set) => descriptor_set,
+            None => return Ok(None),
+        };
+
+        let data = std::fs::read(&descriptor_set).map_err(|err| {
+            anyhow::anyhow!(
+                "Failed to read descriptor set '{}': {}",
+                descriptor_set,
+                err
+            )
+        })?;
+        let pool = DescriptorPool::decode(data.as_slice())
+            .map_err(|err| anyhow::anyhow!("Failed to decode descriptor set: {}", err))?;
+
+        let default_message = match config.message {
+            Some(message) => Some(
+                pool.get_message_by_name(&message)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown protobuf message: {}", message))?,
+            ),
+            None => None,
+        };
+
+        Ok(Some(Self {
+            pool,
+            default_message,
+        }))
+    }
+
+    /// Find the message descriptor, either by the `dataschema` attribute or the default.
+    ///
+    /// The data schema may either be the full name of the message, or a URI ending with it
+    /// (like `type.googleapis.com/<name>`).
+    fn message(&self, dataschema: Option<&str>) -> Option<MessageDescriptor> {
+        dataschema
+            .and_then(|schema| {
+                self.pool.get_message_by_name(schema).or_else(|| {
+                    schema
+                        .rsplit(['/', '#'])
+                        .next()
+                        .and_then(|name| self.pool.get_message_by_name(name))
+                })
+            })
+            .or_else(|| self.default_message.clone())
+    }
+
+    /// Decode a protobuf payload into JSON.
+    pub fn decode(&self, dataschema: Option<&str>, data: &[u8]) -> Result<Value, ServiceError> {
+        let descriptor = self.message(dataschema).ok_or_else(|| {
+            ServiceError::PayloadParse(format!(
+                "Unable to find protobuf message for schema: {:?}",
+                dataschema
+            ))
+        })?;
+
+        let message = DynamicMessage::decode(descriptor, data).map_err(|err| {
+            ServiceError::PayloadParse(format!("Invalid protobuf payload: {}", err))
+        })?;
+
+        // keep the field names of the proto file, and all values
+        let options = SerializeOptions::new()
+            .use_proto_field_name(true)
+            .stringify_64_bit_integers(false)
+            .skip_default_fields(false);
+
+        message
+            .serialize_with_options(serde_json::value::Serializer, &options)
+            .map_err(|err| ServiceError::PayloadParse(err.to_string()))
+    }
+}
+
+/// Decode a CBOR payload into JSON.
+pub fn from_cbor(data: &[u8]) -> Result<Value, ServiceError> {
+    let value: ciborium::value::Value = ciborium::de::from_reader(data)
+        .map_err(|err| ServiceError::PayloadParse(format!("Invalid CBOR payload: {}", err)))?;
+    cbor_to_json(value)
+}
+
+/// Decode a MessagePack payload into JSON.
+pub fn from_msgpack(mut data: &[u8]) -> Result<Value, ServiceError> {
+    let value = rmpv::decode::read_value(&mut data).map_err(|err| {
+        ServiceError::PayloadParse(format!("Invalid MessagePack payload: {}", err))
+    })?;
+    msgpack_to_json(value)
+}
+
+fn float(value: f64) -> Value {
+    // NaN and infinity cannot be represented in JSON
+    Number::from_f64(value)
+        .map(Value::Number)
+        .unwrap_or(Value::Null)
+}
+
+fn cbor_to_json(value: ciborium::value::Value) -> Result<Value, ServiceError> {
+    use ciborium::value::Value as Cbor;
+
+    Ok(match value {
+        Cbor::Null => Value::Null,
+        Cbor::Bool(b) => Value::Bool(b),
+        Cbor::Integer(i) => {
+            let i = i128::from(i);
+            if let Ok(i) = i64::try_from(i) {
+                Value::from(i)
+            } else if let Ok(i) = u64::try_from(i) {
+                Value::from(i)
+            } else {
+                float(i as f64)
+            }
+        }
+        Cbor::Float(f) => float(f),
+        Cbor::Text(s) => Value::String(s),
+        Cbor::Bytes(b) => Value::String(base64::encode(b)),
+        // tags carry semantic information, which we ignore
+        Cbor::Tag(_, value) => cbor_to_json(*value)?,
+        Cbor::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .map(cbor_to_json)
+                .collect::<Result<_, _>>()?,
+        ),
+        Cbor::Map(entries) => {
+            let mut map = Map::with_capacity(entries.len());
+            for (key, value) in entries {
+                let key = match cbor_to_json(key)? {
+                    Value::String(key) => key,
+                    key => key.to_string(),
+                };
+                map.insert(key, cbor_to_json(value)?);
+            }
+            Value::Object(map)
+        }
+        value => {
+            return Err(ServiceError::PayloadParse(format!(
+                "Unsupported CBOR value: {:?}",
+                value
+            )))
+        }
+    })
+}
+
+fn msgpack_to_json(value: rmpv::Value) -> Result<Value, ServiceError> {
+    use rmpv::Value as MsgPack;
+
+    Ok(match value {
+        MsgPack::Nil => Value::Null,
+        MsgPack::Boolean(b) => Value::Bool(b),
+        MsgPack::Integer(i) => match (i.as_i64(), i.as_u64()) {
+            (Some(i), _) => Value::from(i),
+            (_, Some(i)) => Value::from(i),
+            _ => float(i.as_f64().unwrap_or(f64::NAN)),
+        },
+        MsgPack::F32(f) => float(f as f64),
+        MsgPack::F64(f) => float(f),
+        MsgPack::String(s) => match s.into_str() {
+            Some(s) => Value::String(s),
+            None => {
+                return Err(ServiceError::PayloadParse(
+                    "Invalid UTF-8 string in MessagePack payload".into(),
+                ))
+            }
+        },
+        MsgPack::Binary(b) => Value::String(base64::encode(b)),
+        MsgPack::Array(values) => Value::Array(
+            values
+                .into_iter()
+                .map(msgpack_to_json)
+                .collect::<Result<_, _>>()?,
+        ),
+        MsgPack::Map(entries) => {
+            let mut map = Map::with_capacity(entries.len());
+            for (key, value) in entries {
+                let key = match msgpack_to_json(key)? {
+                    Value::String(key) => key,
+                    key => key.to_string(),
+                };
+                map.insert(key, msgpack_to_json(value)?);
+            }
+            Value::Object(map)
+        }
+        MsgPack::Ext(ty, _) => {
+            return Err(ServiceError::PayloadParse(format!(
+                "Unsupported MessagePack extension type: {}",
+                ty
+            )))
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use prost_reflect::{
+        prost::Message,
+        prost_types::{
+            field_descriptor_proto::{Label, Type},
+            DescriptorProto, FieldDescriptorProto, FileDescriptorProto, FileDescriptorSet,
+        },
+    };
+    use serde_json::json;
+
+    fn field(name: &str, number: i32, r#type: Type) -> FieldDescriptorProto {
+        FieldDescriptorProto {
+            name: Some(name.into()),
+            number: Some(number),
+            label: Some(Label::Optional as i32),
+            r#type: Some(r#type as i32),
+            json_name: Some(name.into()),
+            ..Default::default()
+        }
+    }
+
+    /// A decoder for the messages `test.Reading` and `test.Status`.
+    fn protobuf(message: Option<&str>) -> Protobuf {
+        let file = FileDescriptorProto {
+            name: Some("test.proto".into()),
+            package: Some("test".into()),
+            syntax: Some("proto3".into()),
+            message_type: vec![
+                DescriptorProto {
+                    name: Some("Reading".into()),
+                    field: vec![
+                        field("device_id", 1, Type::String),
+                        field("temp", 2, Type::Double),
+                    ],
+                    ..Default::default()
+                },
+                DescriptorProto {
+                    name: Some("Status".into()),
+                    field: vec![field("ok", 1, Type::Bool)],
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+        let pool = DescriptorPool::from_file_descriptor_set(FileDescriptorSet { file: vec![file] })
+            .unwrap();
+        let default_message = message.map(|message| pool.get_message_by_name(message).unwrap());
+
+        Protobuf {
+            pool,
+            default_message,
+        }
+    }
+
+    fn reading(protobuf: &Protobuf) -> Vec<u8> {
+        let mut message =
+            DynamicMessage::new(protobuf.pool.get_message_by_name("test.Reading").unwrap());
+        message.set_field_by_name("device_id", prost_reflect::Value::String("d1".into()));
+        message.set_field_by_name("temp", prost_reflect::Value::F64(21.5));
+        message.encode_to_vec()
+    }
+
+    #[test]
+    fn test_protobuf_decode() {
+        let protobuf = protobuf(Some("test.Reading"));
+        let data = reading(&protobuf);
+
+        assert_eq!(
+            protobuf.decode(None, &data).unwrap(),
+            json!({"device_id": "d1", "temp": 21.5})
+        );
+        // default values are written as well
+        assert_eq!(
+            protobuf.decode(None, &[]).unwrap(),
+            json!({"device_id": "", "temp": 0.0})
+        );
+        assert!(protobuf.decode(None, &[0xff, 0xff]).is_err());
+    }
+
+    #[test]
+    fn test_protobuf_dataschema() {
+        let protobuf = protobuf(Some("test.Status"));
+        let data = reading(&protobuf);
+
+        // the data schema takes precedence over the default message
+        let expected = json!({"device_id": "d1", "temp": 21.5});
+        assert_eq!(
+            protobuf.decode(Some("test.Reading"), &data).unwrap(),
+            expected
+        );
+        assert_eq!(
+            protobuf
+                .decode(Some("type.googleapis.com/test.Reading"), &data)
+                .unwrap(),
+            expected
+        );
+        assert_eq!(
+            protobuf
+                .decode(Some("https://example.com/schema.proto#test.Reading"), &data)
+                .unwrap(),
+            expected
+        );
+        // unknown schemas fall back to the default message
+        assert_eq!(
+            protobuf.decode(Some("test.Unknown"), &[]).unwrap(),
+            json!({"ok": false})
+        );
+
+        // without a default message, the schema must be known
+        let protobuf = self::protobuf(None);
+        assert!(protobuf.decode(None, &data).is_err());
+        assert!(protobuf.decode(Some("test.Unknown"), &data).is_err());
+    }
+
+    #[test]
+    fn test_cbor() {
+        let expected = json!({"temp": 21.5, "count": 42, "ok": true, "tags": ["a", "b"]});
+        let mut data = Vec::new();
+        ciborium::ser::into_writer(&expected, &mut data).unwrap();
+
+        assert_eq!(from_cbor(&data).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_msgpack() {
+        let value = rmpv::Value::Map(vec![
+            (rmpv::Value::from("temp"), rmpv::Value::from(21.5)),
+            (rmpv::Value::from(1), rmpv::Value::from(-3)),
+        ]);
+        let mut data = Vec::new();
+        rmpv::encode::write_value(&mut data, &value).unwrap();
+
+        assert_eq!(from_msgpack(&data).unwrap(), json!({"temp": 21.5, "1": -3}));
+    }
+}
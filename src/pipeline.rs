@@ -0,0 +1,188 @@
+use crate::{
+    config::ConfigFromEnv, dedup, discovery, expected::ErrorPolicy, extract::Processor,
+    http::AuthConfig, payload, queue, senml, source, writer,
+};
+use actix_web::web;
+use serde::Deserialize;
+use std::time::Instant;
+
+/// The name of the pipeline, when no pipelines are configured explicitly.
+const DEFAULT_PIPELINE: &str = "default";
+
+/// The configuration of a single pipeline.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub endpoint: AuthConfig,
+    pub postgresql: writer::Config,
+    #[serde(default)]
+    pub deduplication: dedup::Config,
+    #[serde(default)]
+    pub protobuf: payload::ProtobufConfig,
+    #[serde(default)]
+    pub senml: senml::Config,
+    #[serde(default)]
+    pub discovery: discovery::Config,
+    #[serde(default)]
+    pub disable_try_parse: bool,
+    #[serde(default)]
+    pub report_all_errors: bool,
+    #[serde(default)]
+    pub default_error_policy: ErrorPolicy,
+    #[serde(default)]
+    pub source: source::Config,
+    #[serde(default)]
+    pub acknowledge: queue::Acknowledge,
+    #[serde(default)]
+    pub queue: queue::Config,
+}
+
+/// A pipeline, receiving events on its own path, and writing them to its own target.
+pub struct Pipeline {
+    pub name: String,
+    pub path: String,
+    pub auth: AuthConfig,
+    pub source: source::Config,
+    pub processor: web::Data<Processor>,
+}
+
+impl Pipeline {
+    /// Create the default pipeline, configured without an environment variable prefix, and
+    /// receiving events on `/`.
+    pub fn default_from_env() -> anyhow::Result<Self> {
+        Self::new(DEFAULT_PIPELINE, "/".into(), Config::from_env()?, "")
+    }
+
+    /// Create a named pipeline, configured using variables prefixed with `PIPELINE_<NAME>__`,
+    /// and receiving events on `/pipelines/<name>`.
+    pub fn named_from_env(name: &str) -> anyhow::Result<Self> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            anyhow::bail!(
+                "Invalid pipeline name, only ASCII letters, digits, '-' and '_' are allowed: {}",
+                name
+            );
+        }
+
+        let prefix = env_prefix(name);
+        let config = Config::from_env_prefix(&prefix).map_err(|err| {
+            anyhow::anyhow!("Invalid configuration of pipeline '{}': {}", name, err)
+        })?;
+
+        Self::new(
+            name,
+            format!("/pipelines/{}", name),
+            config,
+            &format!("{}__", prefix),
+        )
+    }
+
+    fn new(name: &str, path: String, config: Config, prefix: &str) -> anyhow::Result<Self> {
+        let auth = config.endpoint.clone();
+        let source = config.source.clone();
+        let processor = Processor::new(name, prefix, config)?;
+
+        log::info!("Created pipeline '{}' on: {}", name, path);
+
+        Ok(Self {
+            name: name.to_string(),
+            path,
+            auth,
+            source,
+            processor: web::Data::new(processor),
+        })
+    }
+}
+
+/// The prefix of the environment variables of a named pipeline, without the trailing separator.
+fn env_prefix(name: &str) -> String {
+    format!("PIPELINE_{}", name.to_uppercase().replace('-', "_"))
+}
+
+/// Create the pipelines, either the named ones from a comma separated list, or the default one.
+pub fn from_env(names: Option<&str>) -> anyhow::Result<Vec<Pipeline>> {
+    let names: Vec<&str> = names
+        .iter()
+        .flat_map(|names| names.split(','))
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect();
+
+    if names.is_empty() {
+        return Ok(vec![Pipeline::default_from_env()?]);
+    }
+
+    // different names may still share the same configuration, e.g. `a-b` and `A_B`
+    for (i, name) in names.iter().enumerate() {
+        for other in &names[..i] {
+            if other == name {
+                anyhow::bail!("Duplicate pipeline: {}", name);
+            }
+            if env_prefix(other) == env_prefix(name) {
+                anyhow::bail!(
+                    "Pipelines '{}' and '{}' would both be configured by {}__",
+                    other,
+                    name,
+                    env_prefix(name)
+                );
+            }
+        }
+    }
+
+    let mut pipelines: Vec<Pipeline> = Vec::with_capacity(names.len());
+    for name in names {
+        pipelines.push(Pipeline::named_from_env(name)?);
+    }
+
+    Ok(pipelines)
+}
+
+/// Start shutting down all pipelines, counting the events which are still written, or dropped.
+pub fn start_draining(pipelines: &[Pipeline]) {
+    for pipeline in pipelines {
+        pipeline.processor.start_draining();
+    }
+}
+
+/// Stop accepting new events, on all pipelines.
+pub fn close(pipelines: &[Pipeline]) {
+    for pipeline in pipelines {
+        pipeline.processor.close();
+    }
+}
+
+/// Drain pending events of all pipelines, until the deadline is reached.
+pub async fn shutdown(pipelines: &[Pipeline], deadline: Instant) {
+    futures::future::join_all(
+        pipelines
+            .iter()
+            .map(|pipeline| pipeline.processor.shutdown(deadline)),
+    )
+    .await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_colliding_names() {
+        let err = |names: &str| match from_env(Some(names)) {
+            Ok(_) => panic!("Expected an error for: {}", names),
+            Err(err) => err.to_string(),
+        };
+
+        assert_eq!(err("a,b,a"), "Duplicate pipeline: a");
+        assert_eq!(
+            err("a-b,a_b"),
+            "Pipelines 'a-b' and 'a_b' would both be configured by PIPELINE_A_B__"
+        );
+        assert_eq!(
+            err("sensors,Sensors"),
+            "Pipelines 'sensors' and 'Sensors' would both be configured by PIPELINE_SENSORS__"
+        );
+    }
+}
@@ -0,0 +1,280 @@
+use crate::{
+    dedup::{Deduplicator, EventKey},
+    error::ServiceError,
+    metrics,
+    shutdown::InFlight,
+    writer::{PostgresInsertion, PostgresWriter},
+};
+use serde::Deserialize;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    task::JoinHandle,
+};
+
+/// When events are acknowledged to the sender.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Acknowledge {
+    /// After the event was written.
+    #[default]
+    Sync,
+    /// After the event was processed and queued for writing.
+    Async,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    /// The number of events which may be queued for writing.
+    #[serde(default = "default_size")]
+    pub size: usize,
+    /// The number of workers, writing queued events.
+    #[serde(default = "default_workers")]
+    pub workers: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            size: default_size(),
+            workers: default_workers(),
+        }
+    }
+}
+
+#[inline]
+fn default_size() -> usize {
+    1000
+}
+
+#[inline]
+fn default_workers() -> usize {
+    4
+}
+
+/// An event, ready to be written.
+pub struct Job {
+    pub insertions: Vec<PostgresInsertion>,
+    /// The identity of the event.
+    pub key: EventKey,
+    /// The table to record the event as processed.
+    pub processed: Option<String>,
+}
+
+/// Queues events, which are written by a pool of workers.
+pub struct Queue {
+    pipeline: String,
+    sender: Mutex<Option<mpsc::Sender<Job>>>,
+    workers: Mutex<Vec<JoinHandle<()>>>,
+    queued: Arc<InFlight>,
+}
+
+impl Queue {
+    pub fn new(
+        pipeline: &str,
+        writer: Arc<PostgresWriter>,
+        deduplicator: Arc<Deduplicator>,
+        config: Config,
+    ) -> anyhow::Result<Self> {
+        if config.size == 0 || config.workers == 0 {
+            anyhow::bail!("Queue size and number of workers must be greater than zero");
+        }
+
+        let (sender, receiver) = mpsc::channel::<Job>(config.size);
+        let receiver = Arc::new(tokio::sync::Mutex::new(receiver));
+        let queued = Arc::new(InFlight::default());
+
+        let workers = (0..config.workers)
+            .map(|_| {
+                let pipeline = pipeline.to_string();
+                let writer = writer.clone();
+                let deduplicator = deduplicator.clone();
+                let receiver = receiver.clone();
+                let queued = queued.clone();
+                actix_web::rt::spawn(async move {
+                    loop {
+                        let job = receiver.lock().await.recv().await;
+                        let job = match job {
+                            Some(job) => job,
+                            None => break,
+                        };
+                        metrics::QUEUED_EVENTS.with_label_values(&[&pipeline]).dec();
+
+                        let key = Some(&job.key);
+                        let processed = job.processed.as_deref();
+                        let outcome = match writer.write(job.insertions, key, processed).await {
+                            Ok(true) => {
+                                // only a written event counts as processed
+                                deduplicator.mark(job.key);
+                                "written"
+                            }
+                            Ok(false) => "duplicate",
+                            Err(err) => {
+                                log::warn!("Failed to write queued event: {}", err);
+                                "failed"
+                            }
+                        };
+                        metrics::QUEUED_WRITES
+                            .with_label_values(&[&pipeline, outcome])
+                            .inc();
+                        queued.leave();
+                    }
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            pipeline: pipeline.to_string(),
+            sender: Mutex::new(Some(sender)),
+            workers: Mutex::new(workers),
+            queued,
+        })
+    }
+
+    /// Queue an event for writing, failing if the queue is full or closed.
+    pub fn enqueue(&self, job: Job) -> Result<(), ServiceError> {
+        let sender = self.sender.lock().unwrap();
+        let sender = sender
+            .as_ref()
+            .ok_or_else(|| ServiceError::Unavailable("Shutting down".into()))?;
+
+        // count before sending, as a worker may pick up the job right away
+        if !self.queued.arrive() {
+            return Err(ServiceError::Unavailable("Shutting down".into()));
+        }
+        metrics::QUEUED_EVENTS
+            .with_label_values(&[&self.pipeline])
+            .inc();
+
+        match sender.try_send(job) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                self.queued.leave();
+                metrics::QUEUED_EVENTS
+                    .with_label_values(&[&self.pipeline])
+                    .dec();
+                Err(match err {
+                    TrySendError::Full(_) => ServiceError::Overloaded("Queue is full".into()),
+                    TrySendError::Closed(_) => ServiceError::Unavailable("Shutting down".into()),
+                })
+            }
+        }
+    }
+
+    /// Start counting queued events, which are written or dropped while shutting down.
+    pub fn start_draining(&self) {
+        self.queued.start_draining();
+    }
+
+    /// Stop accepting new events.
+    pub fn close(&self) {
+        self.queued.close();
+        self.sender.lock().unwrap().take();
+    }
+
+    /// Write the queued events, until the deadline is reached.
+    pub async fn shutdown(&self, deadline: Instant) {
+        self.close();
+
+        let stats = self.queued.drain(deadline).await;
+        if stats.abandoned > 0 {
+            log::warn!(
+                "Pipeline '{}': dropped {} queued event(s) on shutdown",
+                self.pipeline,
+                stats.abandoned
+            );
+        }
+
+        // all workers stopped once the queue is empty, or they are stuck
+        for worker in self.workers.lock().unwrap().drain(..) {
+            worker.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::ResponseError;
+    use chrono::Utc;
+    use serde_json::json;
+    use std::time::Duration;
+
+    /// Create a queue, writing to a database which can't be connected to.
+    fn queue(pipeline: &str, size: usize) -> (Queue, Arc<PostgresWriter>, Arc<Deduplicator>) {
+        let config = serde_json::from_value(json!({
+            "table": "test",
+            "connection": {"host": "localhost", "port": 1, "dbname": "test"},
+        }))
+        .unwrap();
+        let writer = Arc::new(PostgresWriter::new(pipeline, config).unwrap());
+        let deduplicator = Arc::new(Deduplicator::new(
+            serde_json::from_value(json!({"mode": "memory"})).unwrap(),
+        ));
+        let config = Config { size, workers: 1 };
+        let queue = Queue::new(pipeline, writer.clone(), deduplicator.clone(), config).unwrap();
+        (queue, writer, deduplicator)
+    }
+
+    async fn job(writer: &PostgresWriter, id: &str) -> Job {
+        Job {
+            insertions: vec![writer.new_insertion(Utc::now()).await.unwrap()],
+            key: EventKey {
+                source: "test".into(),
+                id: id.into(),
+            },
+            processed: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_full() {
+        let (queue, writer, _) = queue("test-queue-full", 1);
+
+        // the worker doesn't run before yielding, so the first job stays queued
+        queue.enqueue(job(&writer, "1").await).unwrap();
+        let err = queue.enqueue(job(&writer, "2").await).unwrap_err();
+
+        assert_eq!(err.error_response().status(), 429);
+    }
+
+    #[actix_web::test]
+    async fn test_closed() {
+        let (queue, writer, _) = queue("test-queue-closed", 1);
+
+        queue.close();
+        let err = queue.enqueue(job(&writer, "1").await).unwrap_err();
+
+        assert_eq!(err.error_response().status(), 503);
+    }
+
+    #[actix_web::test]
+    async fn test_drain_on_shutdown() {
+        let pipeline = "test-queue-drain";
+        let (queue, writer, deduplicator) = queue(pipeline, 10);
+
+        queue.enqueue(job(&writer, "1").await).unwrap();
+        queue.enqueue(job(&writer, "2").await).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(10);
+        queue.shutdown(deadline).await;
+        assert!(Instant::now() < deadline);
+
+        // both writes were attempted, and failed
+        assert_eq!(
+            metrics::QUEUED_WRITES
+                .with_label_values(&[pipeline, "failed"])
+                .get(),
+            2
+        );
+        assert_eq!(
+            metrics::QUEUED_EVENTS.with_label_values(&[pipeline]).get(),
+            0
+        );
+
+        // failed events are not marked as processed, so they can be delivered again
+        let key = job(&writer, "1").await.key;
+        assert!(!deduplicator.is_duplicate(&key));
+    }
+}
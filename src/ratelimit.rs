@@ -0,0 +1,186 @@
+use lru::LruCache;
+use serde::Deserialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The number of clients to track, dropping the least recently seen ones.
+const MAX_CLIENTS: usize = 10_000;
+
+/// The minimum rate, one event per hour, keeping the delay until the next token sane.
+const MIN_RATE: f64 = 1.0 / 3600.0;
+
+/// What identifies a client.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Key {
+    /// The authenticated principal.
+    Principal,
+    /// The IP address of the peer.
+    #[default]
+    Ip,
+    /// The value of a cloud event attribute (or extension).
+    Attribute,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub key: Key,
+    /// The name of the attribute, when using the `attribute` key.
+    pub attribute: Option<String>,
+    /// The number of events per second, refilling the bucket.
+    pub rate: f64,
+    /// The number of events which may be sent at once, the size of the bucket.
+    pub burst: Option<u32>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Limits the rate of events per client, using a token bucket for each client.
+pub struct RateLimiter {
+    pub key: Key,
+    pub attribute: String,
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<LruCache<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: Config) -> anyhow::Result<Self> {
+        if !config.rate.is_finite() || config.rate < MIN_RATE {
+            anyhow::bail!(
+                "Rate limit must be at least {} events per second (one per hour)",
+                MIN_RATE
+            );
+        }
+
+        let burst = config
+            .burst
+            .map(f64::from)
+            .unwrap_or_else(|| config.rate.ceil())
+            .max(1.0);
+
+        let attribute = match (&config.key, config.attribute) {
+            (Key::Attribute, Some(attribute)) => attribute.to_lowercase(),
+            (Key::Attribute, None) => anyhow::bail!("Missing attribute to rate limit by"),
+            (_, _) => String::new(),
+        };
+
+        Ok(Self {
+            key: config.key,
+            attribute,
+            rate: config.rate,
+            burst,
+            buckets: Mutex::new(LruCache::new(MAX_CLIENTS)),
+        })
+    }
+
+    /// Take a token for the client. If the client exceeded its rate, the time until the next
+    /// token is available is returned.
+    pub fn check(&self, client: &str) -> Result<(), Duration> {
+        self.check_at(client, Instant::now())
+    }
+
+    fn check_at(&self, client: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+
+        // the least recently seen client is dropped, its bucket has most likely been refilled
+        if !buckets.contains(client) {
+            buckets.put(
+                client.to_string(),
+                Bucket {
+                    tokens: self.burst,
+                    updated: now,
+                },
+            );
+        }
+        let bucket = buckets.get_mut(client).expect("bucket was just added");
+
+        if self.refill(bucket, now) >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+
+    fn refill(&self, bucket: &mut Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        bucket.tokens
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket() {
+        let limiter = RateLimiter::new(Config {
+            key: Key::Ip,
+            attribute: None,
+            rate: 2.0,
+            burst: Some(3),
+        })
+        .unwrap();
+
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.check_at("a", now).is_ok());
+        }
+        assert_eq!(limiter.check_at("a", now), Err(Duration::from_millis(500)));
+        // other clients have their own bucket
+        assert!(limiter.check_at("b", now).is_ok());
+        // one token is refilled after half a second
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.check_at("a", later).is_ok());
+        assert!(limiter.check_at("a", later).is_err());
+    }
+
+    fn config(rate: f64) -> Config {
+        Config {
+            key: Key::Ip,
+            attribute: None,
+            rate,
+            burst: None,
+        }
+    }
+
+    #[test]
+    fn test_rate() {
+        assert!(RateLimiter::new(config(1.0 / 3600.0)).is_ok());
+        for rate in &[0.0, -1.0, 1e-300, f64::NAN, f64::INFINITY] {
+            assert!(RateLimiter::new(config(*rate)).is_err(), "{}", rate);
+        }
+
+        // the delay until the next token at the minimum rate
+        let limiter = RateLimiter::new(config(MIN_RATE)).unwrap();
+        let now = Instant::now();
+        assert!(limiter.check_at("a", now).is_ok());
+        assert!(limiter.check_at("a", now).unwrap_err() <= Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn test_max_clients() {
+        let limiter = RateLimiter::new(config(1.0)).unwrap();
+        let now = Instant::now();
+
+        assert!(limiter.check_at("a", now).is_ok());
+        for i in 0..MAX_CLIENTS {
+            assert!(limiter.check_at(&i.to_string(), now).is_ok());
+        }
+
+        // the least recently seen client was dropped, starting over with a full bucket
+        assert_eq!(limiter.buckets.lock().unwrap().len(), MAX_CLIENTS);
+        assert!(limiter.check_at("a", now).is_ok());
+        // others are still limited
+        assert!(limiter
+            .check_at(&(MAX_CLIENTS - 1).to_string(), now)
+            .is_err());
+    }
+}
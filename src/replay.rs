@@ -0,0 +1,356 @@
+//! Replay events from files, for backfilling.
+
+use crate::{
+    error::ServiceError,
+    extract::{Outcome, Processor},
+    pipeline::{self, Pipeline},
+};
+use actix_web::web;
+use cloudevents::Event;
+use flate2::read::MultiGzDecoder;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, clap::Args)]
+pub struct Args {
+    /// Files or directories of newline delimited, structured cloud events, `-` for stdin.
+    ///
+    /// Files ending with `.gz` are decompressed.
+    #[clap(default_value = "-")]
+    pub paths: Vec<PathBuf>,
+    /// The pipeline to process the events with, the default pipeline if not set.
+    #[clap(long)]
+    pub pipeline: Option<String>,
+    /// The number of events to process concurrently.
+    #[clap(long, default_value_t = 4)]
+    pub concurrency: usize,
+    /// The number of lines to read at once, while processing the previous ones.
+    #[clap(long, default_value_t = 100)]
+    pub batch_size: usize,
+    /// The maximum number of events to process per second.
+    #[clap(long)]
+    pub rate: Option<f64>,
+    /// The time to wait for pending writes to complete, after all events were processed.
+    #[clap(long, default_value = "30s", parse(try_from_str = humantime::parse_duration))]
+    pub drain_timeout: Duration,
+}
+
+/// An input to read events from.
+#[derive(Clone, Debug)]
+enum Input {
+    Stdin,
+    File(PathBuf),
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Stdin => f.write_str("<stdin>"),
+            Self::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl Input {
+    fn open(&self) -> io::Result<Box<dyn BufRead + Send>> {
+        Ok(match self {
+            Self::Stdin => Box::new(BufReader::new(io::stdin())),
+            Self::File(path) if path.extension().is_some_and(|ext| ext == "gz") => {
+                Box::new(BufReader::new(MultiGzDecoder::new(File::open(path)?)))
+            }
+            Self::File(path) => Box::new(BufReader::new(File::open(path)?)),
+        })
+    }
+}
+
+/// Collect the inputs, expanding directories to the files they contain, in order of their names.
+fn inputs(paths: &[PathBuf]) -> io::Result<Vec<Input>> {
+    fn expand(path: &Path, inputs: &mut Vec<Input>) -> io::Result<()> {
+        if !path.is_dir() {
+            inputs.push(Input::File(path.to_path_buf()));
+            return Ok(());
+        }
+
+        let mut entries = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        entries.sort();
+        for entry in entries {
+            expand(&entry, inputs)?;
+        }
+        Ok(())
+    }
+
+    let mut inputs = Vec::new();
+    for path in paths {
+        match path.to_str() {
+            Some("-") => inputs.push(Input::Stdin),
+            _ => expand(path, &mut inputs)?,
+        }
+    }
+    Ok(inputs)
+}
+
+/// Counts of processed lines, by their outcome.
+#[derive(Debug, Default)]
+struct Stats {
+    lines: usize,
+    written: usize,
+    no_values: usize,
+    duplicate: usize,
+    filtered: usize,
+    failed: usize,
+}
+
+impl Stats {
+    fn record(&mut self, result: &Result<Outcome, ServiceError>) {
+        self.lines += 1;
+        match result {
+            Ok(Outcome::Written | Outcome::Accepted) => self.written += 1,
+            Ok(Outcome::NoValues) => self.no_values += 1,
+            Ok(Outcome::Duplicate) => self.duplicate += 1,
+            Ok(Outcome::Filtered) => self.filtered += 1,
+            Err(_) => self.failed += 1,
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} lines, written: {}, no values: {}, duplicate: {}, filtered: {}, failed: {}",
+            self.lines, self.written, self.no_values, self.duplicate, self.filtered, self.failed
+        )
+    }
+}
+
+/// Replay events from the inputs, using the pipeline configured in the environment.
+pub async fn run(args: Args) -> anyhow::Result<()> {
+    if args.concurrency == 0 || args.batch_size == 0 {
+        anyhow::bail!("Concurrency and batch size must be greater than zero");
+    }
+
+    let limiter = match args.rate {
+        Some(rate) if rate > 0.0 => {
+            Some(tokio::time::interval(Duration::from_secs_f64(1.0 / rate)))
+        }
+        Some(_) => anyhow::bail!("Rate must be greater than zero"),
+        None => None,
+    };
+
+    let pipeline = match &args.pipeline {
+        Some(name) => Pipeline::named_from_env(name)?,
+        None => Pipeline::default_from_env()?,
+    };
+
+    let mut total = Stats::default();
+    let result = replay_inputs(&args, &pipeline, limiter, &mut total).await;
+
+    // drain pending writes, also when reading the inputs failed
+    let pipelines = std::slice::from_ref(&pipeline);
+    pipeline::close(pipelines);
+    pipeline::shutdown(pipelines, Instant::now() + args.drain_timeout).await;
+
+    log::info!("Replayed {}", total);
+
+    result?;
+    if total.failed > 0 {
+        anyhow::bail!("Failed to replay {} event(s)", total.failed);
+    }
+
+    Ok(())
+}
+
+/// The lines of an input, numbered starting with 0.
+type Lines = std::iter::Enumerate<io::Lines<Box<dyn BufRead + Send>>>;
+
+/// Read the next batch of non-empty lines, numbered starting with 1.
+///
+/// Reading, and decompressing, blocks. So this runs on the blocking thread pool, handing the
+/// lines back for the next batch.
+async fn read_batch(
+    mut lines: Lines,
+    batch_size: usize,
+) -> anyhow::Result<(Lines, Vec<(usize, String)>)> {
+    Ok(tokio::task::spawn_blocking(move || {
+        let mut batch = Vec::with_capacity(batch_size);
+        for (number, line) in lines.by_ref() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                batch.push((number + 1, line));
+                if batch.len() == batch_size {
+                    break;
+                }
+            }
+        }
+        Ok::<_, io::Error>((lines, batch))
+    })
+    .await??)
+}
+
+async fn replay_inputs(
+    args: &Args,
+    pipeline: &Pipeline,
+    mut limiter: Option<tokio::time::Interval>,
+    total: &mut Stats,
+) -> anyhow::Result<()> {
+    let paths = args.paths.clone();
+    let inputs = tokio::task::spawn_blocking(move || inputs(&paths)).await??;
+
+    for input in inputs {
+        let mut stats = Stats::default();
+        let result = replay_input(args, pipeline, &mut limiter, &input, &mut stats)
+            .await
+            .map_err(|err| err.context(format!("Failed to replay {}", input)));
+
+        total.lines += stats.lines;
+        total.written += stats.written;
+        total.no_values += stats.no_values;
+        total.duplicate += stats.duplicate;
+        total.filtered += stats.filtered;
+        total.failed += stats.failed;
+
+        result?;
+    }
+
+    Ok(())
+}
+
+async fn replay_input(
+    args: &Args,
+    pipeline: &Pipeline,
+    limiter: &mut Option<tokio::time::Interval>,
+    input: &Input,
+    stats: &mut Stats,
+) -> anyhow::Result<()> {
+    let opened = input.clone();
+    let mut lines = tokio::task::spawn_blocking(move || opened.open())
+        .await??
+        .lines()
+        .enumerate();
+
+    // events are processed in their own tasks, so that they continue while rate limited, or while
+    // reading the next batch
+    let mut pending = FuturesUnordered::new();
+
+    let result: anyhow::Result<()> = async {
+        loop {
+            let (next, batch) = read_batch(lines, args.batch_size).await?;
+            if batch.is_empty() {
+                return Ok(());
+            }
+            lines = next;
+
+            for (number, line) in batch {
+                if pending.len() >= args.concurrency {
+                    if let Some(result) = pending.next().await {
+                        let (number, result) = result?;
+                        report(input, number, &result, stats);
+                    }
+                }
+                if let Some(limiter) = limiter.as_mut() {
+                    limiter.tick().await;
+                }
+                pending.push(actix_web::rt::spawn(replay(
+                    pipeline.processor.clone(),
+                    number,
+                    line,
+                )));
+            }
+
+            log::info!("{}: {}", input, stats);
+        }
+    }
+    .await;
+
+    // wait for the events already being processed, also when reading the input failed
+    while let Some(next) = pending.next().await {
+        let (number, next) = next?;
+        report(input, number, &next, stats);
+    }
+    log::info!("{}: {}", input, stats);
+
+    result
+}
+
+async fn replay(
+    processor: web::Data<Processor>,
+    number: usize,
+    line: String,
+) -> (usize, Result<Outcome, ServiceError>) {
+    let result = match serde_json::from_str::<Event>(&line) {
+        Ok(event) => processor.process(event).await,
+        Err(err) => Err(ServiceError::PayloadParse(err.to_string())),
+    };
+    (number, result)
+}
+
+fn report(input: &Input, number: usize, result: &Result<Outcome, ServiceError>, stats: &mut Stats) {
+    if let Err(err) = result {
+        log::warn!("{}:{}: {}", input, number, err);
+    }
+    stats.record(result);
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_inputs() {
+        let dir = std::env::temp_dir().join(format!("replay-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        for name in ["b.ndjson", "a.ndjson.gz", "sub/c.ndjson"] {
+            File::create(dir.join(name)).unwrap();
+        }
+
+        let inputs = inputs(&[PathBuf::from("-"), dir.clone()]).unwrap();
+        let names: Vec<String> = inputs
+            .iter()
+            .map(|input| match input {
+                Input::Stdin => "-".to_string(),
+                Input::File(path) => path.strip_prefix(&dir).unwrap().display().to_string(),
+            })
+            .collect();
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(names, vec!["-", "a.ndjson.gz", "b.ndjson", "sub/c.ndjson"]);
+    }
+
+    #[actix_web::test]
+    async fn test_read_batch() {
+        let reader: Box<dyn BufRead + Send> = Box::new(io::Cursor::new("a\n\nb\n  \nc\n"));
+        let lines = reader.lines().enumerate();
+
+        let (lines, batch) = read_batch(lines, 2).await.unwrap();
+        assert_eq!(batch, vec![(1, "a".to_string()), (3, "b".to_string())]);
+        let (lines, batch) = read_batch(lines, 2).await.unwrap();
+        assert_eq!(batch, vec![(5, "c".to_string())]);
+        let (_, batch) = read_batch(lines, 2).await.unwrap();
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn test_args() {
+        #[derive(clap::Parser)]
+        struct Command {
+            #[clap(flatten)]
+            args: Args,
+        }
+
+        let command = <Command as clap::Parser>::parse_from(["replay"]);
+        assert_eq!(command.args.drain_timeout, Duration::from_secs(30));
+        let command = <Command as clap::Parser>::parse_from(["replay", "--drain-timeout", "2m"]);
+        assert_eq!(command.args.drain_timeout, Duration::from_secs(120));
+        assert!(
+            <Command as clap::Parser>::try_parse_from(["replay", "--drain-timeout", "soon"])
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,135 @@
+use crate::{error::ServiceError, jsonata};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fmt::{Debug, Formatter};
+use std::sync::Arc;
+
+/// A compiled selector for values from a JSON structure.
+///
+/// The language is chosen by a prefix (`jsonpath:`, `pointer:`, `jmespath:`, `jsonata:`),
+/// defaulting to JSON path.
+#[derive(Clone)]
+pub enum Selector {
+    JsonPath(jsonpath_lib::Compiled),
+    Pointer(String),
+    JmesPath(Arc<jmespath::Expression<'static>>),
+    JsonAta(Arc<jsonata::Expression>),
+}
+
+impl Selector {
+    /// Select all matching values.
+    pub fn select<'a>(&self, json: &'a Value) -> Result<Vec<Cow<'a, Value>>, ServiceError> {
+        match self {
+            Selector::JsonPath(compiled) => Ok(compiled
+                .select(json)
+                .map_err(|err| ServiceError::Selector(err.to_string()))?
+                .into_iter()
+                .map(Cow::Borrowed)
+                .collect()),
+            Selector::Pointer(pointer) => Ok(json
+                .pointer(pointer)
+                .map(Cow::Borrowed)
+                .into_iter()
+                .collect()),
+            Selector::JmesPath(expression) => {
+                let result = expression
+                    .search(json)
+                    .map_err(|err| ServiceError::Selector(err.to_string()))?;
+                if result.is_null() {
+                    return Ok(vec![]);
+                }
+                let value = serde_json::to_value(&*result)
+                    .map_err(|err| ServiceError::Selector(err.to_string()))?;
+                Ok(vec![Cow::Owned(value)])
+            }
+            Selector::JsonAta(expression) => Ok(expression
+                .evaluate(json)?
+                .map(Cow::Owned)
+                .into_iter()
+                .collect()),
+        }
+    }
+}
+
+impl Debug for Selector {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Selector::JsonPath(compiled) => f.debug_tuple("JsonPath").field(compiled).finish(),
+            Selector::Pointer(pointer) => f.debug_tuple("Pointer").field(pointer).finish(),
+            Selector::JmesPath(expression) => f
+                .debug_tuple("JmesPath")
+                .field(&expression.as_str())
+                .finish(),
+            Selector::JsonAta(expression) => f.debug_tuple("JsonAta").field(expression).finish(),
+        }
+    }
+}
+
+impl TryFrom<&str> for Selector {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        if let Some(pointer) = value.strip_prefix("pointer:") {
+            if !pointer.is_empty() && !pointer.starts_with('/') {
+                anyhow::bail!("JSON pointer must be empty or start with '/': {}", pointer);
+            }
+            Ok(Selector::Pointer(pointer.to_string()))
+        } else if let Some(expression) = value.strip_prefix("jmespath:") {
+            let compiled = jmespath::compile(expression)
+                .map_err(|err| anyhow::anyhow!("Failed to parse JMESPath expression: {}", err))?;
+            Ok(Selector::JmesPath(Arc::new(compiled)))
+        } else if let Some(expression) = value.strip_prefix("jsonata:") {
+            let compiled = jsonata::Expression::try_from(expression)
+                .map_err(|err| anyhow::anyhow!("Failed to parse JSONata expression: {}", err))?;
+            Ok(Selector::JsonAta(Arc::new(compiled)))
+        } else {
+            let path = value.strip_prefix("jsonpath:").unwrap_or(value);
+            let compiled = jsonpath_lib::Compiled::compile(path)
+                .map_err(|err| anyhow::anyhow!("Failed to parse JSON path: {}", err))?;
+            Ok(Selector::JsonPath(compiled))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn select(selector: &str) -> Vec<Value> {
+        let json = json!({"temp": 21.5, "readings": [{"value": 1}, {"value": 2}]});
+        Selector::try_from(selector)
+            .unwrap()
+            .select(&json)
+            .unwrap()
+            .into_iter()
+            .map(Cow::into_owned)
+            .collect()
+    }
+
+    #[test]
+    fn test_select() {
+        assert_eq!(select("$.temp"), vec![json!(21.5)]);
+        assert_eq!(
+            select("jsonpath:$.readings[*].value"),
+            vec![json!(1), json!(2)]
+        );
+        assert_eq!(select("pointer:/readings/1/value"), vec![json!(2)]);
+        assert_eq!(select("pointer:/missing"), Vec::<Value>::new());
+        assert_eq!(select("jmespath:readings[0].value"), vec![json!(1)]);
+        assert_eq!(select("jmespath:readings[*].value"), vec![json!([1, 2])]);
+        assert_eq!(select("jmespath:missing"), Vec::<Value>::new());
+        assert_eq!(select("jsonata:readings[0].value"), vec![json!(1)]);
+        assert_eq!(select("jsonata:$sum(readings.value)"), vec![json!(3)]);
+        assert_eq!(select("jsonata:missing"), Vec::<Value>::new());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Selector::try_from("pointer:temp").is_err());
+        assert!(Selector::try_from("jmespath:readings[").is_err());
+        assert!(Selector::try_from("jsonata:readings[").is_err());
+        assert!(Selector::try_from("$.[").is_err());
+    }
+}
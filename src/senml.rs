@@ -0,0 +1,443 @@
+use crate::{
+    error::ServiceError,
+    writer::{column_name, Type},
+};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::borrow::Cow;
+
+/// Times below this value (2^28) are relative to the current time (RFC 8428, section 4.5.3).
+const RELATIVE_TIME_LIMIT: f64 = 268_435_456.0;
+
+/// Named values of a row.
+type Values<'a> = Vec<(Cow<'a, str>, Type)>;
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Payloads are processed using the configured field paths.
+    #[default]
+    Disabled,
+    /// Write one row per SenML record, with the name and value in dedicated columns.
+    Rows,
+    /// Write one row per distinct record time, with one column per record name.
+    Pivot,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub mode: Mode,
+    /// Column for the record name, in `rows` mode.
+    #[serde(default = "default_name_column")]
+    pub name_column: String,
+    /// Column for numeric values (`v`), in `rows` mode.
+    #[serde(default = "default_value_column")]
+    pub value_column: String,
+    /// Column for string values (`vs`), in `rows` mode.
+    #[serde(default = "default_string_value_column")]
+    pub string_value_column: String,
+    /// Column for boolean values (`vb`), in `rows` mode.
+    #[serde(default = "default_boolean_value_column")]
+    pub boolean_value_column: String,
+    /// Column for data values (`vd`), in `rows` mode.
+    #[serde(default = "default_data_value_column")]
+    pub data_value_column: String,
+    /// Column for the unit, if it should be written.
+    pub unit_column: Option<String>,
+    /// Column for the base name, if it should be written separately from the name.
+    pub base_name_column: Option<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            mode: Default::default(),
+            name_column: default_name_column(),
+            value_column: default_value_column(),
+            string_value_column: default_string_value_column(),
+            boolean_value_column: default_boolean_value_column(),
+            data_value_column: default_data_value_column(),
+            unit_column: None,
+            base_name_column: None,
+        }
+    }
+}
+
+fn default_name_column() -> String {
+    "name".into()
+}
+
+fn default_value_column() -> String {
+    "value_double".into()
+}
+
+fn default_string_value_column() -> String {
+    "value_text".into()
+}
+
+fn default_boolean_value_column() -> String {
+    "value_bool".into()
+}
+
+fn default_data_value_column() -> String {
+    "value_data".into()
+}
+
+/// A resolved SenML record.
+#[derive(Clone, Debug)]
+pub struct Record {
+    /// The base name in effect for this record.
+    pub base_name: String,
+    /// The name of the record, without the base name.
+    pub name: String,
+    pub time: DateTime<Utc>,
+    pub unit: Option<String>,
+    pub value: Type,
+    /// If the value is a (base64 encoded) data value.
+    pub data: bool,
+}
+
+impl Record {
+    /// The full name, concatenating the base name and the name.
+    pub fn full_name(&self) -> String {
+        format!("{}{}", self.base_name, self.name)
+    }
+}
+
+#[derive(Default)]
+struct Base {
+    name: String,
+    time: f64,
+    unit: Option<String>,
+    value: f64,
+}
+
+/// Parse and resolve SenML records.
+///
+/// Base values are applied to all following records. Relative times are resolved against the
+/// provided reference time, which is the time of the event.
+pub fn parse(json: &Value, reference: DateTime<Utc>) -> Result<Vec<Record>, ServiceError> {
+    let records = match json {
+        Value::Array(records) => records.as_slice(),
+        Value::Object(_) => std::slice::from_ref(json),
+        _ => {
+            return Err(ServiceError::PayloadParse(
+                "SenML payload must be an array of records".into(),
+            ))
+        }
+    };
+
+    let mut base = Base::default();
+    let mut result = Vec::with_capacity(records.len());
+
+    for record in records {
+        let record = record
+            .as_object()
+            .ok_or_else(|| ServiceError::PayloadParse("SenML record must be an object".into()))?;
+
+        if let Some(name) = string(record, "bn")? {
+            base.name = name.into();
+        }
+        if let Some(time) = number(record, "bt")? {
+            base.time = time;
+        }
+        if let Some(unit) = string(record, "bu")? {
+            base.unit = Some(unit.into());
+        }
+        if let Some(value) = number(record, "bv")? {
+            base.value = value;
+        }
+
+        let (value, data) = if let Some(value) = number(record, "v")? {
+            (Type::Float(base.value + value), false)
+        } else if let Some(value) = string(record, "vs")? {
+            (Type::String(value.into()), false)
+        } else if let Some(value) = record.get("vb") {
+            let value = value.as_bool().ok_or_else(|| invalid("vb", value))?;
+            (Type::Boolean(value), false)
+        } else if let Some(value) = string(record, "vd")? {
+            (Type::String(value.into()), true)
+        } else {
+            // records may only carry base values, sums are not supported
+            continue;
+        };
+
+        let name = string(record, "n")?.unwrap_or_default();
+        if name.is_empty() && base.name.is_empty() {
+            return Err(ServiceError::PayloadParse(
+                "SenML record without a name".into(),
+            ));
+        }
+
+        let time = base.time + number(record, "t")?.unwrap_or_default();
+
+        result.push(Record {
+            base_name: base.name.clone(),
+            name: name.into(),
+            time: resolve_time(time, reference)?,
+            unit: string(record, "u")?
+                .map(ToString::to_string)
+                .or_else(|| base.unit.clone()),
+            value,
+            data,
+        });
+    }
+
+    Ok(result)
+}
+
+fn invalid(key: &str, value: &Value) -> ServiceError {
+    ServiceError::PayloadParse(format!("Invalid SenML value for '{}': {}", key, value))
+}
+
+fn string<'a>(record: &'a Map<String, Value>, key: &str) -> Result<Option<&'a str>, ServiceError> {
+    match record.get(key) {
+        None => Ok(None),
+        Some(Value::String(value)) => Ok(Some(value)),
+        Some(value) => Err(invalid(key, value)),
+    }
+}
+
+fn number(record: &Map<String, Value>, key: &str) -> Result<Option<f64>, ServiceError> {
+    match record.get(key) {
+        None => Ok(None),
+        Some(value) => value.as_f64().map(Some).ok_or_else(|| invalid(key, value)),
+    }
+}
+
+fn resolve_time(time: f64, reference: DateTime<Utc>) -> Result<DateTime<Utc>, ServiceError> {
+    let result = if time < RELATIVE_TIME_LIMIT {
+        reference.checked_add_signed(Duration::microseconds((time * 1_000_000.0).round() as i64))
+    } else {
+        Utc.timestamp_opt(time.trunc() as i64, (time.fract() * 1_000_000_000.0) as u32)
+            .single()
+    };
+
+    result.ok_or_else(|| ServiceError::PayloadParse(format!("Invalid SenML time: {}", time)))
+}
+
+impl Config {
+    /// Convert the records into rows of fields, based on the mode.
+    ///
+    /// In pivot mode, record names become columns, and must neither match a name in `reserved`
+    /// nor the base name column.
+    pub fn rows<F>(
+        &self,
+        records: Vec<Record>,
+        reserved: F,
+    ) -> Result<Vec<(DateTime<Utc>, Values<'_>)>, ServiceError>
+    where
+        F: Fn(&str) -> bool,
+    {
+        match self.mode {
+            Mode::Disabled => Ok(vec![]),
+            Mode::Rows => Ok(records
+                .into_iter()
+                .map(|record| (record.time, self.row(record)))
+                .collect()),
+            Mode::Pivot => self.pivot(records, reserved),
+        }
+    }
+
+    fn row(&self, record: Record) -> Values<'_> {
+        let mut fields = Vec::with_capacity(4);
+
+        let name = match &self.base_name_column {
+            Some(column) => {
+                fields.push((
+                    Cow::Borrowed(column.as_str()),
+                    Type::String(record.base_name.clone()),
+                ));
+                record.name.clone()
+            }
+            None => record.full_name(),
+        };
+        fields.push((Cow::Borrowed(self.name_column.as_str()), Type::String(name)));
+
+        if let (Some(column), Some(unit)) = (&self.unit_column, record.unit) {
+            fields.push((Cow::Borrowed(column.as_str()), Type::String(unit)));
+        }
+
+        let column = match (&record.value, record.data) {
+            (_, true) => &self.data_value_column,
+            (Type::Float(_), _) => &self.value_column,
+            (Type::Boolean(_), _) => &self.boolean_value_column,
+            _ => &self.string_value_column,
+        };
+        fields.push((Cow::Borrowed(column.as_str()), record.value));
+
+        fields
+    }
+
+    fn pivot<F>(
+        &self,
+        records: Vec<Record>,
+        reserved: F,
+    ) -> Result<Vec<(DateTime<Utc>, Values<'_>)>, ServiceError>
+    where
+        F: Fn(&str) -> bool,
+    {
+        // group by time, and base name if it is written separately, keeping the order of records
+        let mut rows: Vec<(DateTime<Utc>, Option<String>, Values)> = Vec::new();
+
+        for record in records {
+            let base_name = self
+                .base_name_column
+                .as_ref()
+                .map(|_| record.base_name.clone());
+            let name = match (record.name.is_empty(), &base_name) {
+                (true, _) => column_name(&record.base_name),
+                (false, Some(_)) => column_name(&record.name),
+                (false, None) => column_name(&record.full_name()),
+            };
+            if reserved(&name) || self.base_name_column.as_deref() == Some(name.as_str()) {
+                return Err(ServiceError::PayloadParse(format!(
+                    "SenML record collides with column: {}",
+                    name
+                )));
+            }
+
+            let index = match rows
+                .iter()
+                .position(|(time, base, _)| *time == record.time && *base == base_name)
+            {
+                Some(index) => index,
+                None => {
+                    rows.push((record.time, base_name, Vec::new()));
+                    rows.len() - 1
+                }
+            };
+
+            let fields = &mut rows[index].2;
+            // a later record with the same name wins
+            fields.retain(|(field, _)| *field != name);
+            fields.push((Cow::Owned(name), record.value));
+        }
+
+        Ok(rows
+            .into_iter()
+            .map(|(time, base_name, mut fields)| {
+                if let (Some(column), Some(base_name)) = (&self.base_name_column, base_name) {
+                    fields.push((Cow::Borrowed(column.as_str()), Type::String(base_name)));
+                }
+                (time, fields)
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn reference() -> DateTime<Utc> {
+        Utc.timestamp_opt(1_600_000_000, 0).unwrap()
+    }
+
+    #[test]
+    fn test_resolve() {
+        let records = parse(
+            &json!([
+                {"bn": "urn:dev:1:", "bt": 1.32e9, "bu": "Cel", "bv": 1, "n": "temp", "v": 20.5},
+                {"n": "temp", "t": 10, "v": 21},
+                {"n": "label", "vs": "kitchen"},
+                {"bn": "urn:dev:2:", "bt": -5, "n": "open", "vb": true},
+            ]),
+            reference(),
+        )
+        .unwrap();
+
+        assert_eq!(records.len(), 4);
+        assert_eq!(records[0].full_name(), "urn:dev:1:temp");
+        assert_eq!(
+            records[0].time,
+            Utc.timestamp_opt(1_320_000_000, 0).unwrap()
+        );
+        assert_eq!(records[0].unit.as_deref(), Some("Cel"));
+        assert!(matches!(records[0].value, Type::Float(v) if v == 21.5));
+        assert_eq!(
+            records[1].time,
+            Utc.timestamp_opt(1_320_000_010, 0).unwrap()
+        );
+        assert!(matches!(records[1].value, Type::Float(v) if v == 22.0));
+        assert!(matches!(&records[2].value, Type::String(v) if v == "kitchen"));
+        assert_eq!(records[3].full_name(), "urn:dev:2:open");
+        assert_eq!(
+            records[3].time,
+            Utc.timestamp_opt(1_599_999_995, 0).unwrap()
+        );
+        assert!(matches!(records[3].value, Type::Boolean(true)));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(parse(&json!(42), reference()).is_err());
+        assert!(parse(&json!([{"v": 1}]), reference()).is_err());
+        assert!(parse(&json!([{"n": "temp", "v": "1"}]), reference()).is_err());
+    }
+
+    #[test]
+    fn test_invalid_time() {
+        // relative times, out of the range of the reference time
+        assert!(parse(&json!([{"n": "temp", "t": -1e15, "v": 1}]), reference()).is_err());
+        assert!(parse(&json!([{"n": "temp", "t": -1e300, "v": 1}]), reference()).is_err());
+        assert!(parse(
+            &json!([{"bt": -1e300, "n": "temp", "t": -1e300, "v": 1}]),
+            reference()
+        )
+        .is_err());
+        // absolute times, out of range
+        assert!(parse(&json!([{"n": "temp", "t": 1e300, "v": 1}]), reference()).is_err());
+    }
+
+    #[test]
+    fn test_pivot() {
+        let config = Config {
+            mode: Mode::Pivot,
+            base_name_column: Some("device".into()),
+            ..Default::default()
+        };
+        let records = parse(
+            &json!([
+                {"bn": "urn:dev:1:", "n": "temp", "v": 21.5},
+                {"n": "Humidity", "v": 40},
+                {"n": "temp", "t": 5, "v": 22},
+            ]),
+            reference(),
+        )
+        .unwrap();
+
+        let rows = config.rows(records, |_| false).unwrap();
+        let names: Vec<Vec<&str>> = rows
+            .iter()
+            .map(|(_, fields)| fields.iter().map(|(name, _)| name.as_ref()).collect())
+            .collect();
+
+        assert_eq!(
+            names,
+            vec![vec!["temp", "humidity", "device"], vec!["temp", "device"]]
+        );
+    }
+
+    #[test]
+    fn test_pivot_collisions() {
+        let config = Config {
+            mode: Mode::Pivot,
+            base_name_column: Some("device".into()),
+            ..Default::default()
+        };
+        let reserved = |name: &str| name == "time" || name == "room";
+        let rows = |json: Value| config.rows(parse(&json, reference()).unwrap(), reserved);
+
+        assert!(rows(json!([{"bn": "urn:dev:1:", "n": "temp", "v": 21.5}])).is_ok());
+        // the time column
+        assert!(rows(json!([{"n": "Time", "v": 1}])).is_err());
+        // a tag
+        assert!(rows(json!([{"bn": "urn:dev:1:", "n": "room", "vs": "kitchen"}])).is_err());
+        // the base name column
+        assert!(rows(json!([{"n": "device", "vs": "other"}])).is_err());
+    }
+}
@@ -0,0 +1,194 @@
+use std::sync::Mutex;
+use std::time::Instant;
+use tokio::sync::Notify;
+
+/// Wait for a termination signal.
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut term = signal(SignalKind::terminate()).expect("Failed to register signal handler");
+        tokio::select! {
+            _ = term.recv() => {},
+            _ = tokio::signal::ctrl_c() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    /// Number of operations which completed after the shutdown was initiated.
+    pub flushed: usize,
+    /// Number of operations which got cancelled, or did not complete in time.
+    pub abandoned: usize,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Count operations, which complete or get abandoned.
+    draining: bool,
+    closed: bool,
+    active: usize,
+    stats: Stats,
+}
+
+/// Tracks in-flight operations, so that they can be drained on shutdown.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    state: Mutex<State>,
+    idle: Notify,
+}
+
+impl InFlight {
+    /// Enter a new operation, unless the tracker is already closed.
+    pub fn enter(&self) -> Option<Guard<'_>> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return None;
+        }
+        state.active += 1;
+        Some(Guard {
+            in_flight: self,
+            completed: false,
+        })
+    }
+
+    /// Enter a new operation, which is completed by calling `leave`, e.g. from another task.
+    ///
+    /// Returns `false` if the tracker is already closed.
+    pub fn arrive(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return false;
+        }
+        state.active += 1;
+        true
+    }
+
+    /// Complete an operation, entered using `arrive`.
+    pub fn leave(&self) {
+        self.exit(true);
+    }
+
+    fn exit(&self, completed: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.active -= 1;
+        if state.draining {
+            match completed {
+                true => state.stats.flushed += 1,
+                false => state.stats.abandoned += 1,
+            }
+        }
+        if state.active == 0 {
+            self.idle.notify_waiters();
+        }
+    }
+
+    /// Start draining, counting the operations which complete or get abandoned from now on.
+    ///
+    /// New operations are still accepted, until the tracker is closed.
+    pub fn start_draining(&self) {
+        self.state.lock().unwrap().draining = true;
+    }
+
+    /// Close the tracker, rejecting new operations.
+    pub fn close(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.draining = true;
+        state.closed = true;
+    }
+
+    /// Wait until all active operations completed, or the deadline was reached.
+    pub async fn drain(&self, deadline: Instant) -> Stats {
+        let deadline = tokio::time::Instant::from_std(deadline);
+
+        loop {
+            let idle = self.idle.notified();
+            if self.state.lock().unwrap().active == 0 {
+                break;
+            }
+            if tokio::time::timeout_at(deadline, idle).await.is_err() {
+                break;
+            }
+        }
+
+        let state = self.state.lock().unwrap();
+        Stats {
+            flushed: state.stats.flushed,
+            abandoned: state.stats.abandoned + state.active,
+        }
+    }
+}
+
+pub struct Guard<'a> {
+    in_flight: &'a InFlight,
+    completed: bool,
+}
+
+impl Guard<'_> {
+    /// Mark the operation as completed. Dropping the guard without, counts as abandoned.
+    pub fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl Drop for Guard<'_> {
+    fn drop(&mut self) {
+        self.in_flight.exit(self.completed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[actix_web::test]
+    async fn test_drain() {
+        let in_flight = Arc::new(InFlight::default());
+
+        // completed before shutting down
+        in_flight.enter().unwrap().complete();
+
+        // a write, which completes during the grace period
+        let guard = in_flight.clone();
+        let flushed = actix_web::rt::spawn(async move {
+            let guard = guard.enter().unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            guard.complete();
+        });
+        // a write, which fails during the grace period
+        let guard = in_flight.enter().unwrap();
+        // a write, which is still running when the grace period is over
+        let abandoned = in_flight.clone();
+        let abandoned = actix_web::rt::spawn(async move {
+            let _guard = abandoned.enter().unwrap();
+            futures::future::pending::<()>().await;
+        });
+        tokio::task::yield_now().await;
+
+        // the signal arrives, new writes are still accepted
+        in_flight.start_draining();
+        in_flight.enter().unwrap().complete();
+        drop(guard);
+
+        flushed.await.unwrap();
+        abandoned.abort();
+        assert!(abandoned.await.is_err());
+
+        // the server stopped, rejecting new writes
+        in_flight.close();
+        assert!(in_flight.enter().is_none());
+
+        let stats = in_flight
+            .drain(Instant::now() + Duration::from_secs(1))
+            .await;
+        assert_eq!((stats.flushed, stats.abandoned), (2, 2));
+    }
+}
@@ -0,0 +1,176 @@
+use super::{process, stopped};
+use crate::{error::ServiceError, extract::Processor};
+use actix_web::web;
+use cloudevents::{binding::rdkafka::record_to_event, Event};
+use rdkafka::{
+    config::ClientConfig,
+    consumer::{CommitMode, Consumer, StreamConsumer},
+    Message,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::{sync::watch, task::JoinHandle};
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub bootstrap_servers: String,
+    /// Comma separated list of topics to consume from.
+    pub topics: String,
+    pub group_id: String,
+    /// Additional client properties.
+    ///
+    /// As environment variables cannot contain dots, `_` in the name is replaced by `.`.
+    #[serde(default)]
+    pub properties: HashMap<String, String>,
+    /// The delay before retrying an event, which failed with a transient error.
+    #[serde(default = "default_retry_delay", with = "humantime_serde")]
+    pub retry_delay: Duration,
+}
+
+#[inline]
+fn default_retry_delay() -> Duration {
+    Duration::from_secs(5)
+}
+
+/// Start consuming events, using the Kafka protocol binding.
+pub fn start(
+    name: &str,
+    config: Config,
+    processor: web::Data<Processor>,
+    stop: watch::Receiver<bool>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let mut client = ClientConfig::new();
+    for (key, value) in &config.properties {
+        client.set(key.replace('_', "."), value);
+    }
+    // offsets are only committed after processing the event
+    client
+        .set("bootstrap.servers", &config.bootstrap_servers)
+        .set("group.id", &config.group_id)
+        .set("enable.auto.commit", "false");
+
+    let consumer: StreamConsumer = client.create()?;
+
+    let topics: Vec<&str> = config
+        .topics
+        .split(',')
+        .map(str::trim)
+        .filter(|topic| !topic.is_empty())
+        .collect();
+    if topics.is_empty() {
+        anyhow::bail!("No Kafka topics configured for pipeline '{}'", name);
+    }
+    consumer.subscribe(&topics)?;
+
+    log::info!(
+        "Consuming from Kafka topics {:?} for pipeline '{}'",
+        topics,
+        name
+    );
+
+    Ok(actix_web::rt::spawn(run(
+        consumer,
+        processor,
+        config.retry_delay,
+        stop,
+    )))
+}
+
+async fn run(
+    consumer: StreamConsumer,
+    processor: web::Data<Processor>,
+    retry_delay: Duration,
+    mut stop: watch::Receiver<bool>,
+) {
+    loop {
+        let message = tokio::select! {
+            _ = stopped(&mut stop) => break,
+            message = consumer.recv() => message,
+        };
+
+        let message = match message {
+            Ok(message) => message,
+            Err(err) => {
+                log::warn!("Failed to receive Kafka message: {}", err);
+                tokio::time::sleep(retry_delay).await;
+                continue;
+            }
+        };
+
+        let event = to_kafka_event(&message);
+
+        // transient errors are retried in place, so the offset never moves past a failed message
+        if !process("Kafka", &processor, event, retry_delay, &mut stop).await {
+            break;
+        }
+
+        if let Err(err) = consumer.commit_message(&message, CommitMode::Async) {
+            log::warn!("Failed to commit Kafka offset: {}", err);
+        }
+    }
+
+    log::info!("Stopped consuming from Kafka");
+}
+
+/// Convert a Kafka message into an event, in either the structured or the binary mode.
+fn to_kafka_event(message: &impl Message) -> Result<Event, ServiceError> {
+    record_to_event(message).map_err(|err| ServiceError::PayloadParse(err.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cloudevents::{AttributesReader, Data};
+    use rdkafka::message::{OwnedHeaders, OwnedMessage, Timestamp};
+
+    fn message(headers: OwnedHeaders, payload: &[u8]) -> OwnedMessage {
+        OwnedMessage::new(
+            Some(payload.to_vec()),
+            None,
+            "events".into(),
+            Timestamp::NotAvailable,
+            0,
+            0,
+            Some(headers),
+        )
+    }
+
+    #[test]
+    fn test_binary_mode() {
+        let headers = OwnedHeaders::new()
+            .add("ce_specversion", "1.0")
+            .add("ce_id", "1")
+            .add("ce_source", "drogue")
+            .add("ce_type", "io.drogue.event.v1")
+            .add("ce_device", "device1")
+            .add("content-type", "application/json");
+        let event = to_kafka_event(&message(headers, br#"{"temp": 21.5}"#)).unwrap();
+
+        assert_eq!(event.id(), "1");
+        assert_eq!(event.source().to_string(), "drogue");
+        assert_eq!(event.ty(), "io.drogue.event.v1");
+        assert_eq!(event.datacontenttype(), Some("application/json"));
+        assert!(event.extension("device").is_some());
+        assert!(matches!(event.data(), Some(Data::Json(_))));
+    }
+
+    #[test]
+    fn test_structured_mode() {
+        let headers = OwnedHeaders::new().add("content-type", "application/cloudevents+json");
+        let payload = br#"{"specversion": "1.0", "id": "1", "source": "drogue", "type": "io.drogue.event.v1", "data": {"temp": 21.5}}"#;
+        let event = to_kafka_event(&message(headers, payload)).unwrap();
+
+        assert_eq!(event.id(), "1");
+        assert!(matches!(event.data(), Some(Data::Json(_))));
+    }
+
+    #[test]
+    fn test_not_an_event() {
+        let headers = OwnedHeaders::new().add("content-type", "application/json");
+        assert!(matches!(
+            to_kafka_event(&message(headers, b"{}")),
+            Err(ServiceError::PayloadParse(_))
+        ));
+    }
+}
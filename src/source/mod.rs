@@ -0,0 +1,334 @@
+//! Sources, receiving events for a pipeline, in addition to the HTTP endpoint.
+
+#[cfg(feature = "amqp")]
+pub mod amqp;
+#[cfg(feature = "kafka")]
+pub mod kafka;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "websocket")]
+pub mod websocket;
+
+use crate::{error::ServiceError, extract::Processor, pipeline::Pipeline};
+use actix_web::web;
+use cloudevents::Event;
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::time::{Duration, Instant};
+use tokio::{sync::watch, task::JoinHandle};
+
+/// The sources of a pipeline, which are enabled by features.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Config {
+    #[cfg(feature = "amqp")]
+    pub amqp: Option<amqp::Config>,
+    #[cfg(feature = "kafka")]
+    pub kafka: Option<kafka::Config>,
+    #[cfg(feature = "mqtt")]
+    pub mqtt: Option<mqtt::Config>,
+    #[cfg(feature = "websocket")]
+    pub websocket: Option<websocket::Config>,
+}
+
+impl Config {
+    // without any source features enabled, there is nothing to start
+    #[allow(unused_mut, unused_variables)]
+    fn start(
+        &self,
+        name: &str,
+        processor: &web::Data<Processor>,
+        stop: &watch::Receiver<bool>,
+    ) -> anyhow::Result<Vec<JoinHandle<()>>> {
+        let mut handles = Vec::new();
+
+        #[cfg(feature = "kafka")]
+        if let Some(config) = &self.kafka {
+            handles.push(kafka::start(
+                name,
+                config.clone(),
+                processor.clone(),
+                stop.clone(),
+            )?);
+        }
+
+        #[cfg(feature = "amqp")]
+        if let Some(config) = &self.amqp {
+            handles.push(amqp::start(
+                name,
+                config.clone(),
+                processor.clone(),
+                stop.clone(),
+            )?);
+        }
+
+        #[cfg(feature = "mqtt")]
+        if let Some(config) = &self.mqtt {
+            handles.push(mqtt::start(
+                name,
+                config.clone(),
+                processor.clone(),
+                stop.clone(),
+            )?);
+        }
+
+        #[cfg(feature = "websocket")]
+        if let Some(config) = &self.websocket {
+            handles.push(websocket::start(
+                name,
+                config.clone(),
+                processor.clone(),
+                stop.clone(),
+            )?);
+        }
+
+        Ok(handles)
+    }
+}
+
+/// Start the sources of all pipelines. They run until `true` is sent to the stop channel.
+pub fn start(
+    pipelines: &[Pipeline],
+    stop: watch::Receiver<bool>,
+) -> anyhow::Result<Vec<JoinHandle<()>>> {
+    let mut handles = Vec::new();
+    for pipeline in pipelines {
+        handles.extend(
+            pipeline
+                .source
+                .start(&pipeline.name, &pipeline.processor, &stop)?,
+        );
+    }
+    Ok(handles)
+}
+
+/// Wait for the sources to finish processing their current events, until the deadline is reached.
+pub async fn shutdown(handles: Vec<JoinHandle<()>>, deadline: Instant) {
+    if handles.is_empty() {
+        return;
+    }
+
+    let deadline = tokio::time::Instant::from_std(deadline);
+    if tokio::time::timeout_at(deadline, futures::future::join_all(handles))
+        .await
+        .is_err()
+    {
+        log::warn!("Sources did not stop in time");
+    }
+}
+
+/// Wait until the sources should stop.
+// only used by sources, which are optional features
+#[allow(dead_code)]
+async fn stopped(stop: &mut watch::Receiver<bool>) {
+    while !*stop.borrow() {
+        if stop.changed().await.is_err() {
+            // sender is gone, nothing can stop us anymore
+            futures::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Process an event, retrying transient errors in place.
+///
+/// Returns `false` if the source was stopped before the event could be processed, in which case
+/// the message must not be acknowledged, so that it gets delivered again.
+// only used by sources, which are optional features
+#[allow(dead_code)]
+async fn process(
+    source: &str,
+    processor: &Processor,
+    event: Result<Event, ServiceError>,
+    retry_delay: Duration,
+    stop: &mut watch::Receiver<bool>,
+) -> bool {
+    let event = match event {
+        Ok(event) => event,
+        Err(err) => {
+            log::info!("Dropping {} message, failed to read event: {}", source, err);
+            return true;
+        }
+    };
+
+    loop {
+        match processor.process_sync(event.clone()).await {
+            Ok(_) => return true,
+            Err(err) if err.is_transient() => {
+                log::warn!("Failed to process {} message, retrying: {}", source, err);
+                tokio::select! {
+                    _ = stopped(stop) => return false,
+                    _ = tokio::time::sleep(retry_delay) => {},
+                }
+            }
+            Err(err) => {
+                // retrying won't help, so skip the message
+                log::info!("Dropping {} message, failed processing: {}", source, err);
+                return true;
+            }
+        }
+    }
+}
+
+/// Convert a message into an event, in either the structured or the binary mode of a protocol
+/// binding.
+///
+/// In the binary mode, the attributes are carried as properties of the message, and the content
+/// type of the message is the content type of the data.
+// only used by sources, which are optional features
+#[allow(dead_code)]
+fn to_event(
+    content_type: Option<&str>,
+    properties: &[(String, String)],
+    payload: &[u8],
+) -> Result<Event, ServiceError> {
+    let parse_error = |err: serde_json::Error| ServiceError::PayloadParse(err.to_string());
+
+    if content_type
+        .is_some_and(|content_type| content_type.starts_with("application/cloudevents+json"))
+    {
+        return serde_json::from_slice(payload).map_err(parse_error);
+    }
+
+    if !properties.iter().any(|(name, _)| name == "specversion") {
+        return Err(ServiceError::PayloadParse(
+            "Message is not a cloud event, missing 'specversion' attribute".into(),
+        ));
+    }
+
+    let mut event: Map<String, Value> = properties
+        .iter()
+        .map(|(name, value)| (name.to_lowercase(), Value::String(value.clone())))
+        .collect();
+
+    let content_type = content_type
+        .map(ToString::to_string)
+        .or_else(|| {
+            event
+                .get("datacontenttype")
+                .and_then(Value::as_str)
+                .map(ToString::to_string)
+        })
+        .unwrap_or_else(|| "application/json".into());
+
+    if !payload.is_empty() {
+        let json = content_type == "application/json" || content_type.ends_with("+json");
+        match json {
+            true => event.insert(
+                "data".into(),
+                serde_json::from_slice(payload).map_err(parse_error)?,
+            ),
+            false => event.insert("data_base64".into(), Value::String(base64::encode(payload))),
+        };
+    }
+    event.insert("datacontenttype".into(), Value::String(content_type));
+
+    serde_json::from_value(Value::Object(event)).map_err(parse_error)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::pipeline;
+    use cloudevents::{AttributesReader, Data, EventBuilder, EventBuilderV10};
+    use serde_json::json;
+
+    /// A processor, which can't connect to its database.
+    pub(super) fn processor(name: &str) -> Processor {
+        processor_acknowledging(name, "sync")
+    }
+
+    fn processor_acknowledging(name: &str, acknowledge: &str) -> Processor {
+        let config: pipeline::Config = serde_json::from_value(json!({
+            "postgresql": {
+                "table": "test",
+                "connection": {"host": "localhost", "port": 1, "dbname": "test"},
+            },
+            "senml": {"mode": "pivot"},
+            "acknowledge": acknowledge,
+        }))
+        .unwrap();
+        Processor::new(name, "TEST_SOURCE_", config).unwrap()
+    }
+
+    pub(super) fn event(data: Value) -> Event {
+        EventBuilderV10::new()
+            .id("1")
+            .source("test")
+            .ty("test")
+            .data("application/json", data)
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_binary_mode() {
+        let properties = vec![
+            ("specversion".to_string(), "1.0".to_string()),
+            ("id".to_string(), "1".to_string()),
+            ("source".to_string(), "drogue".to_string()),
+            ("type".to_string(), "io.drogue.event.v1".to_string()),
+            ("device".to_string(), "device1".to_string()),
+        ];
+
+        let event = to_event(Some("application/json"), &properties, br#"{"temp": 21.5}"#).unwrap();
+
+        assert_eq!(event.id(), "1");
+        assert_eq!(event.ty(), "io.drogue.event.v1");
+        assert_eq!(event.datacontenttype(), Some("application/json"));
+        assert!(event.extension("device").is_some());
+        assert!(matches!(event.data(), Some(Data::Json(_))));
+
+        assert!(to_event(Some("application/json"), &[], b"{}").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_process_retries_until_stopped() {
+        let processor = processor("test-source-retry");
+        let (sender, mut stop) = watch::channel(false);
+        let event = event(json!([{"n": "temp", "v": 21.5}]));
+
+        actix_web::rt::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let _ = sender.send(true);
+        });
+
+        // the write keeps failing transiently, so the message must not be acknowledged
+        let delay = Duration::from_millis(10);
+        assert!(!process("test", &processor, Ok(event), delay, &mut stop).await);
+    }
+
+    #[actix_web::test]
+    async fn test_process_async_acknowledge() {
+        let processor = processor_acknowledging("test-source-async", "async");
+        let (sender, mut stop) = watch::channel(false);
+        let event = event(json!([{"n": "temp", "v": 21.5}]));
+
+        actix_web::rt::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            let _ = sender.send(true);
+        });
+
+        // the event is written right away, instead of being queued, and so fails
+        let delay = Duration::from_millis(10);
+        assert!(!process("test", &processor, Ok(event), delay, &mut stop).await);
+        assert_eq!(
+            crate::metrics::EVENTS
+                .with_label_values(&["test-source-async", "accepted"])
+                .get(),
+            0
+        );
+        processor.shutdown(Instant::now()).await;
+    }
+
+    #[actix_web::test]
+    async fn test_process_drops_invalid() {
+        let processor = processor("test-source-drop");
+        let (_sender, mut stop) = watch::channel(false);
+        let delay = Duration::from_millis(10);
+
+        // neither an unreadable message, nor an invalid payload, are retried
+        let unreadable = Err(ServiceError::PayloadParse("invalid".into()));
+        assert!(process("test", &processor, unreadable, delay, &mut stop).await);
+        let invalid = Ok(event(json!({"temp": 21.5})));
+        assert!(process("test", &processor, invalid, delay, &mut stop).await);
+    }
+}
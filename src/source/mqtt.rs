@@ -0,0 +1,397 @@
+use super::{process, stopped, to_event};
+use crate::{error::ServiceError, extract::Processor};
+use actix_web::web;
+use async_trait::async_trait;
+use cloudevents::Event;
+use rumqttc::{v5, Transport};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Version {
+    /// MQTT 3.1.1, payloads must be cloud events in the structured (JSON) mode.
+    V3,
+    /// MQTT 5, with cloud event attributes as user properties, or in the structured mode.
+    #[default]
+    V5,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    pub host: String,
+    #[serde(default = "default_port")]
+    pub port: u16,
+    #[serde(default)]
+    pub tls: bool,
+    pub client_id: String,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// Comma separated list of topic filters to subscribe to.
+    pub topics: String,
+    #[serde(default)]
+    pub version: Version,
+    /// Start with a new session, dropping messages which were queued while disconnected.
+    #[serde(default)]
+    pub clean_session: bool,
+    #[serde(default = "default_keep_alive", with = "humantime_serde")]
+    pub keep_alive: Duration,
+    /// The maximum number of received messages, waiting to be processed.
+    ///
+    /// This should match the inflight window of the broker, which limits the number of messages
+    /// not acknowledged yet.
+    #[serde(default = "default_inflight")]
+    pub inflight: usize,
+    /// The delay before retrying an event, which failed with a transient error.
+    #[serde(default = "default_retry_delay", with = "humantime_serde")]
+    pub retry_delay: Duration,
+}
+
+#[inline]
+fn default_port() -> u16 {
+    1883
+}
+
+#[inline]
+fn default_keep_alive() -> Duration {
+    Duration::from_secs(30)
+}
+
+#[inline]
+fn default_inflight() -> usize {
+    100
+}
+
+#[inline]
+fn default_retry_delay() -> Duration {
+    Duration::from_secs(5)
+}
+
+impl Config {
+    fn topics(&self, name: &str) -> anyhow::Result<Vec<String>> {
+        let topics: Vec<String> = self
+            .topics
+            .split(',')
+            .map(str::trim)
+            .filter(|topic| !topic.is_empty())
+            .map(ToString::to_string)
+            .collect();
+        if topics.is_empty() {
+            anyhow::bail!("No MQTT topics configured for pipeline '{}'", name);
+        }
+        Ok(topics)
+    }
+
+    fn transport(&self) -> Transport {
+        match self.tls {
+            true => Transport::Tls(rumqttc::TlsConfiguration::Native),
+            false => Transport::Tcp,
+        }
+    }
+}
+
+/// Start subscribing to events, using MQTT 3.1.1 or 5.
+///
+/// Messages are only acknowledged after the event was processed.
+pub fn start(
+    name: &str,
+    config: Config,
+    processor: web::Data<Processor>,
+    stop: watch::Receiver<bool>,
+) -> anyhow::Result<JoinHandle<()>> {
+    let topics = config.topics(name)?;
+    if config.inflight == 0 {
+        anyhow::bail!("MQTT inflight window must be greater than zero");
+    }
+
+    log::info!(
+        "Subscribing to MQTT topics {:?} on {}:{} for pipeline '{}'",
+        topics,
+        config.host,
+        config.port,
+        name
+    );
+
+    Ok(match config.version {
+        Version::V3 => {
+            actix_web::rt::spawn(run::<rumqttc::EventLoop>(config, topics, processor, stop))
+        }
+        Version::V5 => actix_web::rt::spawn(run::<v5::EventLoop>(config, topics, processor, stop)),
+    })
+}
+
+/// A message received from the broker.
+enum Incoming<M> {
+    Connected,
+    Message(M),
+    Other,
+}
+
+/// The parts which differ between the MQTT versions.
+#[async_trait]
+trait Session: Sized + Send + 'static {
+    type Client: Clone + Send + Sync + 'static;
+    type Message: Send + 'static;
+
+    fn connect(config: &Config) -> (Self::Client, Self);
+    async fn poll(&mut self) -> Result<Incoming<Self::Message>, String>;
+    fn subscribe(client: &Self::Client, topic: &str) -> Result<(), String>;
+    fn to_event(message: &Self::Message) -> Result<Event, ServiceError>;
+    async fn ack(client: &Self::Client, message: &Self::Message) -> Result<(), String>;
+    fn disconnect(client: &Self::Client);
+}
+
+#[async_trait]
+impl Session for rumqttc::EventLoop {
+    type Client = rumqttc::AsyncClient;
+    type Message = rumqttc::Publish;
+
+    fn connect(config: &Config) -> (Self::Client, Self) {
+        let mut options = rumqttc::MqttOptions::new(&config.client_id, &config.host, config.port);
+        options
+            .set_transport(config.transport())
+            .set_clean_session(config.clean_session)
+            .set_keep_alive(config.keep_alive)
+            .set_manual_acks(true);
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            options.set_credentials(username, password);
+        }
+
+        rumqttc::AsyncClient::new(options, 10)
+    }
+
+    async fn poll(&mut self) -> Result<Incoming<Self::Message>, String> {
+        use rumqttc::{Event, Packet};
+
+        Ok(match self.poll().await.map_err(|err| err.to_string())? {
+            Event::Incoming(Packet::ConnAck(..)) => Incoming::Connected,
+            Event::Incoming(Packet::Publish(publish)) => Incoming::Message(publish),
+            _ => Incoming::Other,
+        })
+    }
+
+    fn subscribe(client: &Self::Client, topic: &str) -> Result<(), String> {
+        client
+            .try_subscribe(topic, rumqttc::QoS::AtLeastOnce)
+            .map_err(|err| err.to_string())
+    }
+
+    /// MQTT 3.1.1 has no properties, so only the structured mode is supported.
+    fn to_event(publish: &Self::Message) -> Result<Event, ServiceError> {
+        serde_json::from_slice::<Event>(&publish.payload)
+            .map_err(|err| ServiceError::PayloadParse(err.to_string()))
+    }
+
+    async fn ack(client: &Self::Client, publish: &Self::Message) -> Result<(), String> {
+        client.ack(publish).await.map_err(|err| err.to_string())
+    }
+
+    fn disconnect(client: &Self::Client) {
+        let _ = client.try_disconnect();
+    }
+}
+
+#[async_trait]
+impl Session for v5::EventLoop {
+    type Client = v5::AsyncClient;
+    type Message = (
+        v5::mqttbytes::Publish,
+        Option<v5::mqttbytes::PublishProperties>,
+    );
+
+    fn connect(config: &Config) -> (Self::Client, Self) {
+        let mut options = v5::MqttOptions::new(&config.client_id, &config.host, config.port);
+        options
+            .set_transport(config.transport())
+            .set_clean_session(config.clean_session)
+            .set_keep_alive(config.keep_alive)
+            .set_manual_acks(true);
+        if let (Some(username), Some(password)) = (&config.username, &config.password) {
+            options.set_credentials(username, password);
+        }
+
+        v5::AsyncClient::new(options, 10)
+    }
+
+    async fn poll(&mut self) -> Result<Incoming<Self::Message>, String> {
+        use v5::{mqttbytes::v5::Packet, Event};
+
+        Ok(match self.poll().await.map_err(|err| err.to_string())? {
+            Event::Incoming(packet) => match *packet {
+                Packet::ConnAck(..) => Incoming::Connected,
+                Packet::Publish(publish, properties) => Incoming::Message((publish, properties)),
+                _ => Incoming::Other,
+            },
+            _ => Incoming::Other,
+        })
+    }
+
+    fn subscribe(client: &Self::Client, topic: &str) -> Result<(), String> {
+        client
+            .try_subscribe(topic, v5::mqttbytes::QoS::AtLeastOnce)
+            .map_err(|err| err.to_string())
+    }
+
+    fn to_event((publish, properties): &Self::Message) -> Result<Event, ServiceError> {
+        let (content_type, properties) = match properties {
+            Some(properties) => (
+                properties.content_type.as_deref(),
+                properties.user_properties.as_slice(),
+            ),
+            None => (None, &[][..]),
+        };
+        to_event(content_type, properties, &publish.payload)
+    }
+
+    async fn ack(client: &Self::Client, (publish, _): &Self::Message) -> Result<(), String> {
+        client.ack(publish).await.map_err(|err| err.to_string())
+    }
+
+    fn disconnect(client: &Self::Client) {
+        let _ = client.try_disconnect();
+    }
+}
+
+async fn run<S: Session>(
+    config: Config,
+    topics: Vec<String>,
+    processor: web::Data<Processor>,
+    mut stop: watch::Receiver<bool>,
+) {
+    let (client, mut session) = S::connect(&config);
+
+    // the event loop must be polled while processing, for sending acknowledgements and pings,
+    // but stops reading messages once the inflight window is used up
+    let (tx, mut rx) = mpsc::channel(config.inflight);
+    let poll_client = client.clone();
+    let retry_delay = config.retry_delay;
+    let poller = actix_web::rt::spawn(async move {
+        loop {
+            match session.poll().await {
+                Ok(Incoming::Connected) => {
+                    log::info!("Connected to MQTT broker");
+                    for topic in &topics {
+                        if let Err(err) = S::subscribe(&poll_client, topic) {
+                            log::warn!("Failed to subscribe to '{}': {}", topic, err);
+                        }
+                    }
+                }
+                Ok(Incoming::Message(message)) => {
+                    if tx.send(message).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(Incoming::Other) => {}
+                Err(err) => {
+                    log::warn!("MQTT connection failed: {}", err);
+                    tokio::time::sleep(retry_delay).await;
+                }
+            }
+        }
+    });
+
+    loop {
+        let message = tokio::select! {
+            _ = stopped(&mut stop) => break,
+            message = rx.recv() => match message {
+                Some(message) => message,
+                None => break,
+            },
+        };
+
+        let event = S::to_event(&message);
+
+        if !process("MQTT", &processor, event, config.retry_delay, &mut stop).await {
+            break;
+        }
+
+        if let Err(err) = S::ack(&client, &message).await {
+            log::warn!("Failed to acknowledge MQTT message: {}", err);
+        }
+    }
+
+    S::disconnect(&client);
+    poller.abort();
+
+    log::info!("Stopped subscribing to MQTT");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use cloudevents::{AttributesReader, Data};
+
+    const STRUCTURED: &[u8] = br#"{"specversion": "1.0", "id": "1", "source": "drogue", "type": "io.drogue.event.v1", "data": {"temp": 21.5}}"#;
+
+    fn properties(
+        content_type: Option<&str>,
+        user_properties: Vec<(&str, &str)>,
+    ) -> Option<v5::mqttbytes::PublishProperties> {
+        Some(v5::mqttbytes::PublishProperties {
+            payload_format_indicator: None,
+            message_expiry_interval: None,
+            topic_alias: None,
+            response_topic: None,
+            correlation_data: None,
+            user_properties: user_properties
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            subscription_identifiers: vec![],
+            content_type: content_type.map(ToString::to_string),
+        })
+    }
+
+    fn v5_publish(payload: &'static [u8]) -> v5::mqttbytes::Publish {
+        v5::mqttbytes::Publish::new("events", v5::mqttbytes::QoS::AtLeastOnce, payload)
+    }
+
+    #[test]
+    fn test_v3_structured_mode() {
+        let publish = rumqttc::Publish::new("events", rumqttc::QoS::AtLeastOnce, STRUCTURED);
+        let event = rumqttc::EventLoop::to_event(&publish).unwrap();
+
+        assert_eq!(event.id(), "1");
+        assert_eq!(event.source().to_string(), "drogue");
+        assert!(matches!(event.data(), Some(Data::Json(_))));
+
+        let publish = rumqttc::Publish::new("events", rumqttc::QoS::AtLeastOnce, &b"{}"[..]);
+        assert!(rumqttc::EventLoop::to_event(&publish).is_err());
+    }
+
+    #[test]
+    fn test_v5_binary_mode() {
+        let properties = properties(
+            Some("application/json"),
+            vec![
+                ("specversion", "1.0"),
+                ("id", "1"),
+                ("source", "drogue"),
+                ("type", "io.drogue.event.v1"),
+                ("device", "device1"),
+            ],
+        );
+        let message = (v5_publish(br#"{"temp": 21.5}"#), properties);
+        let event = v5::EventLoop::to_event(&message).unwrap();
+
+        assert_eq!(event.id(), "1");
+        assert_eq!(event.ty(), "io.drogue.event.v1");
+        assert_eq!(event.datacontenttype(), Some("application/json"));
+        assert!(event.extension("device").is_some());
+        assert!(matches!(event.data(), Some(Data::Json(_))));
+    }
+
+    #[test]
+    fn test_v5_structured_mode() {
+        let properties = properties(Some("application/cloudevents+json"), vec![]);
+        let event = v5::EventLoop::to_event(&(v5_publish(STRUCTURED), properties)).unwrap();
+        assert_eq!(event.id(), "1");
+
+        // without properties, the payload is not an event
+        assert!(v5::EventLoop::to_event(&(v5_publish(br#"{"temp": 21.5}"#), None)).is_err());
+    }
+}
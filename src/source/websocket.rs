@@ -0,0 +1,356 @@
+use super::{process, stopped};
+use crate::{error::ServiceError, extract::Processor};
+use actix_web::web;
+use awc::ws::{Frame, Message, WebsocketsRequest};
+use cloudevents::Event;
+use futures::{SinkExt, StreamExt};
+use serde::Deserialize;
+use std::time::Duration;
+use tokio::{sync::watch, task::JoinHandle};
+use url::Url;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Config {
+    /// The base URL of the Drogue Cloud API, e.g. `wss://api.sandbox.drogue.cloud`.
+    pub url: String,
+    /// The application to receive events for.
+    pub application: String,
+    /// The consumer group, sharing the events of the application between all of its members.
+    pub group_id: Option<String>,
+    /// The user, authenticating with an access token.
+    pub username: Option<String>,
+    /// The access token of the user.
+    pub token: Option<String>,
+    /// A bearer token, used when no username is set.
+    pub bearer_token: Option<String>,
+    /// The maximum size of a frame, and so of an event.
+    #[serde(default = "default_max_frame_size")]
+    pub max_frame_size: usize,
+    /// The initial delay before reconnecting, doubling with each failed attempt.
+    #[serde(default = "default_retry_delay", with = "humantime_serde")]
+    pub retry_delay: Duration,
+    #[serde(default = "default_max_retry_delay", with = "humantime_serde")]
+    pub max_retry_delay: Duration,
+}
+
+#[inline]
+fn default_max_frame_size() -> usize {
+    256 * 1024
+}
+
+#[inline]
+fn default_retry_delay() -> Duration {
+    Duration::from_secs(1)
+}
+
+#[inline]
+fn default_max_retry_delay() -> Duration {
+    Duration::from_secs(60)
+}
+
+impl Config {
+    /// The URL of the integration endpoint, with the application and group ID encoded.
+    fn endpoint(&self) -> anyhow::Result<Url> {
+        let invalid = || anyhow::anyhow!("Invalid WebSocket URL: {}", self.url);
+        let mut url = Url::parse(&self.url).map_err(|_| invalid())?;
+        url.path_segments_mut()
+            .map_err(|_| invalid())?
+            .pop_if_empty()
+            .extend(&["api", "integration", "v1", &self.application]);
+        if let Some(group_id) = &self.group_id {
+            url.query_pairs_mut().append_pair("group_id", group_id);
+        }
+        Ok(url)
+    }
+
+    /// Create the request for connecting to the endpoint.
+    fn request(&self) -> anyhow::Result<WebsocketsRequest> {
+        let request = awc::Client::new()
+            .ws(self.endpoint()?.as_str())
+            .max_frame_size(self.max_frame_size);
+        Ok(match (&self.username, &self.token, &self.bearer_token) {
+            (Some(username), Some(token), _) => request.basic_auth(username, Some(token)),
+            (None, _, Some(token)) => request.bearer_auth(token),
+            _ => request,
+        })
+    }
+}
+
+/// Start receiving events from the WebSocket integration of Drogue Cloud.
+pub fn start(
+    name: &str,
+    config: Config,
+    processor: web::Data<Processor>,
+    stop: watch::Receiver<bool>,
+) -> anyhow::Result<JoinHandle<()>> {
+    if config.username.is_some() && config.token.is_none() {
+        anyhow::bail!(
+            "Missing WebSocket access token for user, in pipeline '{}'",
+            name
+        );
+    }
+    config.endpoint()?;
+
+    log::info!(
+        "Receiving events of application '{}' from {} for pipeline '{}'",
+        config.application,
+        config.url,
+        name
+    );
+
+    Ok(actix_web::rt::spawn(run(config, processor, stop)))
+}
+
+async fn run(config: Config, processor: web::Data<Processor>, mut stop: watch::Receiver<bool>) {
+    let mut delay = config.retry_delay;
+
+    loop {
+        let mut connected = false;
+        match receive(&config, &processor, &mut stop, &mut connected).await {
+            Ok(()) => break,
+            Err(err) => log::warn!("WebSocket connection failed: {}", err),
+        }
+
+        // start over with the initial delay, once a connection was established
+        if connected {
+            delay = config.retry_delay;
+        }
+        log::info!("Reconnecting in {:?}", delay);
+        tokio::select! {
+            _ = stopped(&mut stop) => break,
+            _ = tokio::time::sleep(delay) => {},
+        }
+        delay = (delay * 2).min(config.max_retry_delay);
+    }
+
+    log::info!("Stopped receiving from WebSocket");
+}
+
+/// Receive and process events, until stopped or the connection fails.
+async fn receive(
+    config: &Config,
+    processor: &Processor,
+    stop: &mut watch::Receiver<bool>,
+    connected: &mut bool,
+) -> anyhow::Result<()> {
+    let (_, mut socket) = config
+        .request()?
+        .connect()
+        .await
+        .map_err(|err| anyhow::anyhow!("Failed to connect: {}", err))?;
+
+    *connected = true;
+    log::info!("Connected to WebSocket endpoint");
+
+    loop {
+        let frame = tokio::select! {
+            _ = stopped(stop) => {
+                let _ = socket.send(Message::Close(None)).await;
+                return Ok(());
+            }
+            frame = socket.next() => frame,
+        };
+
+        match frame {
+            None => anyhow::bail!("Connection closed"),
+            Some(Err(err)) => return Err(err.into()),
+            Some(Ok(Frame::Text(data) | Frame::Binary(data))) => {
+                if !process(
+                    "WebSocket",
+                    processor,
+                    to_event(&data),
+                    config.retry_delay,
+                    stop,
+                )
+                .await
+                {
+                    return Ok(());
+                }
+            }
+            Some(Ok(Frame::Ping(data))) => socket.send(Message::Pong(data)).await?,
+            Some(Ok(Frame::Close(reason))) => {
+                anyhow::bail!("Connection closed by server: {:?}", reason)
+            }
+            Some(Ok(_)) => {}
+        }
+    }
+}
+
+/// Read a structured cloud event from the content of a frame.
+fn to_event(data: &[u8]) -> Result<Event, ServiceError> {
+    serde_json::from_slice(data).map_err(|err| ServiceError::PayloadParse(err.to_string()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{metrics, source::test::processor};
+    use serde_json::json;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpStream,
+    };
+
+    fn config(config: serde_json::Value) -> Config {
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[test]
+    fn test_endpoint() {
+        let config = config(json!({
+            "url": "wss://api.drogue.cloud/",
+            "application": "my-app",
+            "group_id": "pusher",
+        }));
+
+        assert_eq!(
+            config.endpoint().unwrap().as_str(),
+            "wss://api.drogue.cloud/api/integration/v1/my-app?group_id=pusher"
+        );
+    }
+
+    #[test]
+    fn test_endpoint_encoded() {
+        let config = config(json!({
+            "url": "wss://api.drogue.cloud/prefix",
+            "application": "my app/../x",
+            "group_id": "a&b=c",
+        }));
+
+        assert_eq!(
+            config.endpoint().unwrap().as_str(),
+            "wss://api.drogue.cloud/prefix/api/integration/v1/my%20app%2F..%2Fx?group_id=a%26b%3Dc"
+        );
+
+        let config = Config {
+            url: "not a url".into(),
+            ..config
+        };
+        assert!(config.endpoint().is_err());
+    }
+
+    /// A text frame, as sent by the server, without masking.
+    fn frame(event: serde_json::Value) -> Vec<u8> {
+        let payload = event.to_string().into_bytes();
+        let mut frame = vec![0x81];
+        match payload.len() {
+            len if len < 126 => frame.push(len as u8),
+            len => {
+                frame.push(126);
+                frame.extend_from_slice(&(len as u16).to_be_bytes());
+            }
+        }
+        frame.extend(payload);
+        frame
+    }
+
+    /// Accept a single WebSocket connection, and send the frames. Returns the request head, and the
+    /// connection, so that it stays open.
+    async fn stand_in(
+        listener: tokio::net::TcpListener,
+        frames: Vec<Vec<u8>>,
+    ) -> (String, TcpStream) {
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let mut request = Vec::new();
+        let mut buffer = [0u8; 1024];
+        while !request.ends_with(b"\r\n\r\n") {
+            let n = stream.read(&mut buffer).await.unwrap();
+            assert!(n > 0, "Connection closed before the end of the request");
+            request.extend_from_slice(&buffer[..n]);
+        }
+        let request = String::from_utf8(request).unwrap();
+
+        let key = request
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.eq_ignore_ascii_case("sec-websocket-key"))
+            .map(|(_, value)| value.trim())
+            .unwrap();
+        let accept =
+            openssl::sha::sha1(format!("{}258EAFA5-E914-47DA-95CA-C5AB0DC85B11", key).as_bytes());
+        let response = format!(
+            "HTTP/1.1 101 Switching Protocols\r\nupgrade: websocket\r\nconnection: upgrade\r\nsec-websocket-accept: {}\r\n\r\n",
+            base64::encode(accept)
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        for frame in frames {
+            stream.write_all(&frame).await.unwrap();
+        }
+
+        (request, stream)
+    }
+
+    #[actix_web::test]
+    async fn test_connect() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = actix_web::rt::spawn(stand_in(listener, vec![]));
+
+        let config = config(json!({
+            "url": format!("ws://127.0.0.1:{}", port),
+            "application": "my app",
+            "group_id": "a&b",
+            "username": "user",
+            "token": "secret",
+        }));
+        let (response, _socket) = config.request().unwrap().connect().await.unwrap();
+        assert_eq!(response.status(), 101);
+
+        let (request, _) = server.await.unwrap();
+        let mut lines = request.lines();
+        assert_eq!(
+            lines.next(),
+            Some("GET /api/integration/v1/my%20app?group_id=a%26b HTTP/1.1")
+        );
+        let authorization = format!("Basic {}", base64::encode("user:secret"));
+        assert!(lines.any(|line| matches!(
+            line.split_once(':'),
+            Some((name, value)) if name.eq_ignore_ascii_case("authorization") && value.trim() == authorization
+        )));
+    }
+
+    #[actix_web::test]
+    async fn test_receive() {
+        let event = |id: &str, data: serde_json::Value| json!({"specversion": "1.0", "id": id, "source": "test", "type": "test", "data": data});
+        let frames = vec![
+            // no values, so nothing is written
+            frame(event("1", json!([]))),
+            // fails transiently, as the database is unavailable
+            frame(event("2", json!([{"n": "temp", "v": 21.5}]))),
+            frame(event("3", json!([]))),
+        ];
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = actix_web::rt::spawn(stand_in(listener, frames));
+
+        let config = config(json!({
+            "url": format!("ws://127.0.0.1:{}", port),
+            "application": "my-app",
+            "retry_delay": "10ms",
+        }));
+        let processor = processor("test-websocket-receive");
+        let (sender, mut stop) = watch::channel(false);
+        actix_web::rt::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            let _ = sender.send(true);
+        });
+
+        let mut connected = false;
+        receive(&config, &processor, &mut stop, &mut connected)
+            .await
+            .unwrap();
+        assert!(connected);
+        let _connection = server.await.unwrap();
+
+        // the failing frame is retried until stopped, and the one after it is never processed
+        let events = |outcome: &str| {
+            metrics::EVENTS
+                .with_label_values(&["test-websocket-receive", outcome])
+                .get()
+        };
+        assert_eq!(events("no_values"), 1);
+        assert!(events("failed") >= 2);
+    }
+}
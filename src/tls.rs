@@ -0,0 +1,265 @@
+//! Serving HTTPS, optionally authenticating clients by their certificate.
+
+use actix_tls::accept::openssl::TlsStream;
+use actix_web::{dev::Extensions, rt::net::TcpStream};
+use openssl::{
+    nid::Nid,
+    sha::sha256,
+    ssl::{
+        SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod,
+        SslVerifyMode,
+    },
+    x509::{X509NameRef, X509Ref},
+};
+use serde::Deserialize;
+use std::any::Any;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct TlsConfig {
+    /// The PEM encoded certificate chain of the server.
+    pub certificate: PathBuf,
+    /// The PEM encoded private key of the server.
+    pub key: PathBuf,
+    /// PEM encoded CA certificates, verifying the certificates of clients.
+    pub client_ca: Option<PathBuf>,
+    /// Reject connections of clients without a certificate.
+    #[serde(default)]
+    pub require_client_certificate: bool,
+    /// The interval to check the files for changes, reloading them.
+    #[serde(default = "default_reload_interval", with = "humantime_serde")]
+    pub reload_interval: Duration,
+}
+
+#[inline]
+fn default_reload_interval() -> Duration {
+    Duration::from_secs(30)
+}
+
+impl TlsConfig {
+    fn files(&self) -> impl Iterator<Item = &PathBuf> {
+        std::iter::once(&self.certificate)
+            .chain(std::iter::once(&self.key))
+            .chain(self.client_ca.iter())
+    }
+
+    /// Hash the content of the files, as modification times may be too coarse to notice changes.
+    fn fingerprint(&self) -> Vec<Option<[u8; 32]>> {
+        self.files()
+            .map(|path| std::fs::read(path).ok().map(|content| sha256(&content)))
+            .collect()
+    }
+}
+
+/// The verified certificate of a client, stored in the connection data.
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+    /// The subject common name, followed by the subject alternative names.
+    pub names: Vec<String>,
+}
+
+fn builder(config: &TlsConfig) -> anyhow::Result<SslAcceptorBuilder> {
+    let mut builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls_server())?;
+    builder.set_certificate_chain_file(&config.certificate)?;
+    builder.set_private_key_file(&config.key, SslFiletype::PEM)?;
+    builder.check_private_key()?;
+
+    if let Some(client_ca) = &config.client_ca {
+        builder.set_ca_file(client_ca)?;
+        let mut mode = SslVerifyMode::PEER;
+        if config.require_client_certificate {
+            mode |= SslVerifyMode::FAIL_IF_NO_PEER_CERT;
+        }
+        builder.set_verify(mode);
+    }
+
+    Ok(builder)
+}
+
+/// Create the acceptor for the HTTP server, which picks up changes of the files.
+///
+/// Existing connections keep using the previous certificate, new connections use the reloaded
+/// one. If reloading fails, the previous certificate stays in use.
+pub fn acceptor(config: &TlsConfig) -> anyhow::Result<SslAcceptorBuilder> {
+    let current = Arc::new(RwLock::new(builder(config)?.build().into_context()));
+
+    let mut acceptor = builder(config)?;
+    let context = current.clone();
+    // called for every handshake, with or without SNI, switching to the current context
+    acceptor.set_servername_callback(move |ssl, _| {
+        ssl.set_ssl_context(&context.read().unwrap())
+            .map_err(|_| SniError::ALERT_FATAL)
+    });
+
+    actix_web::rt::spawn(reload(config.clone(), current));
+
+    Ok(acceptor)
+}
+
+async fn reload(config: TlsConfig, current: Arc<RwLock<SslContext>>) {
+    let mut fingerprint = config.fingerprint();
+    let mut interval = tokio::time::interval(config.reload_interval);
+
+    loop {
+        interval.tick().await;
+
+        let next = config.fingerprint();
+        if next == fingerprint {
+            continue;
+        }
+        // files may be updated one after the other, a failed attempt is retried once they change
+        // again
+        fingerprint = next;
+
+        match builder(&config) {
+            Ok(builder) => {
+                *current.write().unwrap() = builder.build().into_context();
+                log::info!("Reloaded TLS certificate");
+            }
+            Err(err) => log::warn!("Failed to reload TLS certificate: {}", err),
+        }
+    }
+}
+
+/// Store the certificate of the client in the connection data, if the client provided one.
+pub fn on_connect(connection: &dyn Any, data: &mut Extensions) {
+    if let Some(stream) = connection.downcast_ref::<TlsStream<TcpStream>>() {
+        if let Some(certificate) = stream.ssl().peer_certificate() {
+            data.insert(ClientCertificate {
+                names: names(&certificate),
+            });
+        }
+    }
+}
+
+/// Get the subject common name, and the DNS, email and URI subject alternative names.
+fn names(certificate: &X509Ref) -> Vec<String> {
+    let alt_names = certificate
+        .subject_alt_names()
+        .into_iter()
+        .flatten()
+        .filter_map(|name| {
+            name.dnsname()
+                .or_else(|| name.email())
+                .or_else(|| name.uri())
+                .map(String::from)
+        });
+
+    common_names(certificate.subject_name())
+        .chain(alt_names)
+        .collect()
+}
+
+fn common_names(name: &X509NameRef) -> impl Iterator<Item = String> + '_ {
+    name.entries_by_nid(Nid::COMMONNAME)
+        .filter_map(|entry| entry.data().to_string().ok())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use openssl::{
+        asn1::Asn1Time,
+        ec::{EcGroup, EcKey},
+        hash::MessageDigest,
+        pkey::PKey,
+        x509::{extension::SubjectAlternativeName, X509Builder, X509NameBuilder},
+    };
+    use std::fs;
+
+    /// Write a self-signed certificate, and its key, to the files of the configuration.
+    fn write(config: &TlsConfig, common_name: &str) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, common_name)
+            .unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        fs::write(&config.certificate, builder.build().to_pem().unwrap()).unwrap();
+        fs::write(&config.key, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_names() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_nid(Nid::ORGANIZATIONNAME, "Drogue IoT")
+            .unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, "device1")
+            .unwrap();
+        let name = name.build();
+
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        let alt_names = SubjectAlternativeName::new()
+            .dns("device1.example.com")
+            .email("device1@example.com")
+            .build(&builder.x509v3_context(None, None))
+            .unwrap();
+        builder.append_extension(alt_names).unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+
+        assert_eq!(
+            names(&builder.build()),
+            vec!["device1", "device1.example.com", "device1@example.com"]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_reload() {
+        // removed when dropped, also if an assertion fails
+        let dir = tempfile::tempdir().unwrap();
+        let config = TlsConfig {
+            certificate: dir.path().join("tls.crt"),
+            key: dir.path().join("tls.key"),
+            client_ca: None,
+            require_client_certificate: false,
+            reload_interval: Duration::from_millis(10),
+        };
+        let wait = || tokio::time::sleep(Duration::from_millis(100));
+
+        write(&config, "server1");
+        let current = Arc::new(RwLock::new(
+            builder(&config).unwrap().build().into_context(),
+        ));
+        let reloading = actix_web::rt::spawn(reload(config.clone(), current.clone()));
+        let common_name = || {
+            let context = current.read().unwrap();
+            common_names(context.certificate().unwrap().subject_name()).collect::<Vec<_>>()
+        };
+        wait().await;
+        assert_eq!(common_name(), vec!["server1"]);
+
+        // swap the files
+        write(&config, "server2");
+        wait().await;
+        assert_eq!(common_name(), vec!["server2"]);
+
+        // keep the previous certificate, if the files are broken
+        fs::write(&config.key, "broken").unwrap();
+        wait().await;
+        assert_eq!(common_name(), vec!["server2"]);
+
+        reloading.abort();
+    }
+}
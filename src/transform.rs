@@ -0,0 +1,352 @@
+use crate::{error::ServiceError, writer::Type};
+use regex::Regex;
+use std::collections::HashMap;
+use std::convert::{TryFrom, TryInto};
+use std::env::VarError;
+
+/// A single step of a transformation.
+#[derive(Debug, Clone)]
+pub enum Step {
+    Scale(f64),
+    Offset(f64),
+    Convert {
+        factor: f64,
+        offset: f64,
+    },
+    Round(i32),
+    Clamp {
+        min: Option<f64>,
+        max: Option<f64>,
+    },
+    Lowercase,
+    Uppercase,
+    Trim,
+    Regex(Regex),
+    Lookup {
+        table: HashMap<String, String>,
+        default: Option<String>,
+    },
+}
+
+/// A pipeline of transformation steps, applied to a value after it has been converted.
+#[derive(Debug, Clone, Default)]
+pub struct Transform {
+    pub steps: Vec<Step>,
+}
+
+impl Transform {
+    pub fn apply(&self, value: Type) -> Result<Type, ServiceError> {
+        self.steps
+            .iter()
+            .try_fold(value, |value, step| step.apply(value))
+    }
+}
+
+impl Step {
+    fn apply(&self, value: Type) -> Result<Type, ServiceError> {
+        // a NULL value stays NULL
+        if let Type::Null = value {
+            return Ok(value);
+        }
+
+        match self {
+            Step::Scale(factor) => number(&value).map(|v| Type::Float(v * factor)),
+            Step::Offset(offset) => number(&value).map(|v| Type::Float(v + offset)),
+            Step::Convert { factor, offset } => {
+                number(&value).map(|v| Type::Float(v * factor + offset))
+            }
+            Step::Round(digits) => {
+                let factor = 10f64.powi(*digits);
+                number(&value).map(|v| Type::Float((v * factor).round() / factor))
+            }
+            Step::Clamp { min, max } => number(&value).map(|mut v| {
+                if let Some(min) = min {
+                    v = v.max(*min);
+                }
+                if let Some(max) = max {
+                    v = v.min(*max);
+                }
+                Type::Float(v)
+            }),
+            Step::Lowercase => string(value).map(|s| Type::String(s.to_lowercase())),
+            Step::Uppercase => string(value).map(|s| Type::String(s.to_uppercase())),
+            Step::Trim => string(value).map(|s| Type::String(s.trim().to_string())),
+            Step::Regex(regex) => {
+                let s = string(value)?;
+                let captures = regex.captures(&s).ok_or_else(|| {
+                    ServiceError::Conversion(format!("Value '{}' does not match: {}", s, regex))
+                })?;
+                // use the first group, or the full match if there is none
+                let m = captures.get(1).or_else(|| captures.get(0));
+                Ok(Type::String(
+                    m.map(|m| m.as_str()).unwrap_or_default().to_string(),
+                ))
+            }
+            Step::Lookup { table, default } => {
+                let key = match value {
+                    Type::Boolean(v) => v.to_string(),
+                    Type::Float(v) => v.to_string(),
+                    Type::SignedInteger(v) => v.to_string(),
+                    Type::UnsignedInteger(v) => v.to_string(),
+                    Type::String(v) => v,
+                    Type::Null => unreachable!(),
+                };
+                table
+                    .get(&key)
+                    .or(default.as_ref())
+                    .map(|v| Type::String(v.clone()))
+                    .ok_or_else(|| {
+                        ServiceError::Conversion(format!("No lookup entry for: {}", key))
+                    })
+            }
+        }
+    }
+}
+
+fn number(value: &Type) -> Result<f64, ServiceError> {
+    match value {
+        Type::Float(v) => Ok(*v),
+        Type::SignedInteger(v) => Ok(*v as f64),
+        Type::UnsignedInteger(v) => Ok(*v as f64),
+        _ => Err(ServiceError::Conversion(format!(
+            "Expected numeric value, found: {:?}",
+            value
+        ))),
+    }
+}
+
+fn string(value: Type) -> Result<String, ServiceError> {
+    match value {
+        Type::String(v) => Ok(v),
+        _ => Err(ServiceError::Conversion(format!(
+            "Expected string value, found: {:?}",
+            value
+        ))),
+    }
+}
+
+/// Get the dimension, and factor and offset for converting to the base unit.
+fn unit(name: &str) -> Option<(&'static str, f64, f64)> {
+    Some(match name.to_lowercase().as_str() {
+        // temperature, based on kelvin
+        "k" | "kelvin" => ("temperature", 1.0, 0.0),
+        "c" | "celsius" => ("temperature", 1.0, 273.15),
+        "f" | "fahrenheit" => ("temperature", 5.0 / 9.0, 273.15 - 32.0 * 5.0 / 9.0),
+        // length, based on meters
+        "m" => ("length", 1.0, 0.0),
+        "mm" => ("length", 0.001, 0.0),
+        "cm" => ("length", 0.01, 0.0),
+        "km" => ("length", 1000.0, 0.0),
+        "in" => ("length", 0.0254, 0.0),
+        "ft" => ("length", 0.3048, 0.0),
+        "mi" => ("length", 1609.344, 0.0),
+        // pressure, based on pascal
+        "pa" => ("pressure", 1.0, 0.0),
+        "hpa" => ("pressure", 100.0, 0.0),
+        "kpa" => ("pressure", 1000.0, 0.0),
+        "bar" => ("pressure", 100_000.0, 0.0),
+        "psi" => ("pressure", 6894.757, 0.0),
+        // speed, based on meters per second
+        "mps" => ("speed", 1.0, 0.0),
+        "kmh" => ("speed", 1.0 / 3.6, 0.0),
+        "mph" => ("speed", 0.44704, 0.0),
+        // mass, based on kilograms
+        "kg" => ("mass", 1.0, 0.0),
+        "g" => ("mass", 0.001, 0.0),
+        "lb" => ("mass", 0.453_592_37, 0.0),
+        _ => return None,
+    })
+}
+
+fn parse_number(step: &str, value: &str) -> anyhow::Result<f64> {
+    value
+        .trim()
+        .parse()
+        .map_err(|err| anyhow::anyhow!("Invalid argument for '{}': {} ({})", step, value, err))
+}
+
+fn parse_bound(step: &str, value: Option<&str>) -> anyhow::Result<Option<f64>> {
+    match value.map(str::trim) {
+        None | Some("") => Ok(None),
+        Some(value) => parse_number(step, value).map(Some),
+    }
+}
+
+impl TryFrom<&str> for Step {
+    type Error = anyhow::Error;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let (name, args) = match value.split_once(':') {
+            Some((name, args)) => (name.trim(), Some(args)),
+            None => (value.trim(), None),
+        };
+
+        Ok(match (name.to_lowercase().as_str(), args) {
+            ("scale", Some(args)) => Step::Scale(parse_number(name, args)?),
+            ("offset", Some(args)) => Step::Offset(parse_number(name, args)?),
+            ("convert", Some(args)) => {
+                let (from, to) = args
+                    .split_once(':')
+                    .ok_or_else(|| anyhow::anyhow!("Expected 'convert:<from>:<to>'"))?;
+                let (from_dim, from_factor, from_offset) =
+                    unit(from).ok_or_else(|| anyhow::anyhow!("Unknown unit: {}", from))?;
+                let (to_dim, to_factor, to_offset) =
+                    unit(to).ok_or_else(|| anyhow::anyhow!("Unknown unit: {}", to))?;
+                if from_dim != to_dim {
+                    anyhow::bail!(
+                        "Unable to convert {} ({}) to {} ({})",
+                        from,
+                        from_dim,
+                        to,
+                        to_dim
+                    );
+                }
+                // combine: from -> base -> to
+                Step::Convert {
+                    factor: from_factor / to_factor,
+                    offset: (from_offset - to_offset) / to_factor,
+                }
+            }
+            ("round", None) => Step::Round(0),
+            ("round", Some(args)) => Step::Round(args.trim().parse()?),
+            ("clamp", Some(args)) => {
+                let mut bounds = args.splitn(2, ':');
+                Step::Clamp {
+                    min: parse_bound(name, bounds.next())?,
+                    max: parse_bound(name, bounds.next())?,
+                }
+            }
+            ("lower" | "lowercase", None) => Step::Lowercase,
+            ("upper" | "uppercase", None) => Step::Uppercase,
+            ("trim", None) => Step::Trim,
+            ("regex", Some(args)) => Step::Regex(Regex::new(args)?),
+            ("lookup", Some(args)) => {
+                let mut table = HashMap::new();
+                let mut default = None;
+                for entry in args.split(',') {
+                    let (key, value) = entry
+                        .split_once('=')
+                        .ok_or_else(|| anyhow::anyhow!("Invalid lookup entry: {}", entry))?;
+                    match key.trim() {
+                        "*" => default = Some(value.trim().to_string()),
+                        key => {
+                            table.insert(key.to_string(), value.trim().to_string());
+                        }
+                    }
+                }
+                Step::Lookup { table, default }
+            }
+            _ => anyhow::bail!("Unknown or invalid transformation step: {}", value),
+        })
+    }
+}
+
+impl TryFrom<String> for Transform {
+    type Error = anyhow::Error;
+
+    /// Parse a transformation, steps are separated by `|`, which can be escaped using `\|`.
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let mut steps = Vec::new();
+        let mut current = String::new();
+
+        let mut chars = value.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '\\' if chars.peek() == Some(&'|') => {
+                    current.push('|');
+                    chars.next();
+                }
+                '|' => steps.push(std::mem::take(&mut current)),
+                c => current.push(c),
+            }
+        }
+        steps.push(current);
+
+        let steps = steps
+            .iter()
+            .filter(|step| !step.trim().is_empty())
+            .map(|step| Step::try_from(step.as_str()))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Transform { steps })
+    }
+}
+
+impl TryFrom<Result<String, VarError>> for Transform {
+    type Error = anyhow::Error;
+
+    fn try_from(value: Result<String, VarError>) -> Result<Self, Self::Error> {
+        match value {
+            Ok(value) => value.try_into(),
+            Err(VarError::NotPresent) => Ok(Default::default()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn apply(transform: &str, value: Type) -> Type {
+        Transform::try_from(transform.to_string())
+            .unwrap()
+            .apply(value)
+            .unwrap()
+    }
+
+    fn assert_float(value: Type, expected: f64) {
+        match value {
+            Type::Float(v) => assert!((v - expected).abs() < 1e-9, "{} != {}", v, expected),
+            _ => panic!("Expected float, found: {:?}", value),
+        }
+    }
+
+    #[test]
+    fn test_linear() {
+        assert_float(
+            apply("scale:0.1|offset:-40", Type::UnsignedInteger(650)),
+            25.0,
+        );
+    }
+
+    #[test]
+    fn test_convert() {
+        assert_float(apply("convert:f:c", Type::Float(212.0)), 100.0);
+        assert_float(apply("convert:c:k", Type::Float(0.0)), 273.15);
+        assert_float(apply("convert:hpa:bar", Type::Float(1013.0)), 1.013);
+    }
+
+    #[test]
+    fn test_round_clamp() {
+        assert_float(apply("round:1", Type::Float(1.2345)), 1.2);
+        assert_float(apply("clamp:0:100", Type::Float(120.0)), 100.0);
+        assert_float(apply("clamp::100", Type::Float(-5.0)), -5.0);
+    }
+
+    #[test]
+    fn test_string() {
+        assert!(matches!(
+            apply(r"trim|regex:^v(\d+)", Type::String(" v42 ".into())),
+            Type::String(s) if s == "42"
+        ));
+        assert!(matches!(
+            apply(r"regex:(a\|b)$", Type::String("xb".into())),
+            Type::String(s) if s == "b"
+        ));
+        assert!(matches!(
+            apply("lookup:0=off,1=on,*=unknown", Type::SignedInteger(1)),
+            Type::String(s) if s == "on"
+        ));
+        assert!(matches!(
+            apply("lookup:0=off,*=unknown|upper", Type::SignedInteger(2)),
+            Type::String(s) if s == "UNKNOWN"
+        ));
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(Transform::try_from("convert:c:m".to_string()).is_err());
+        assert!(Transform::try_from("foo".to_string()).is_err());
+    }
+}
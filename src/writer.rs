@@ -1,23 +1,21 @@
-use crate::error::ServiceError;
-use async_trait::async_trait;
+use crate::{dedup::EventKey, error::ServiceError, metrics, shutdown::InFlight};
+use bytes::BytesMut;
 use chrono::{DateTime, Utc};
-use deadpool::managed::PoolError;
-use deadpool_postgres::Pool;
+use deadpool_postgres::{Client, Pool, PoolConfig, PoolError, Runtime};
+use lru::LruCache;
 use rust_decimal::Decimal;
 use serde::Deserialize;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::{sync::mpsc, task::JoinHandle};
 use tokio_postgres::{
-    types::{ToSql, Type as PgType},
+    types::{to_sql_checked, IsNull, ToSql, Type as PgType},
     NoTls,
 };
 
-#[async_trait]
-pub trait Writer<'a> {
-    type Insertion: Insertion<'a>;
-
-    fn new_insertion(&self, timestamp: DateTime<Utc>) -> Self::Insertion;
-    async fn write(&self, insertion: Self::Insertion) -> Result<(), ServiceError>;
-}
-
 pub trait Insertion<'a> {
     fn add_field(self, field: &str, value: Type) -> Self;
     fn add_tag(self, tag: &str, value: Type) -> Self;
@@ -29,34 +27,372 @@ pub struct Config {
     #[serde(default = "default_time_column")]
     pub time_column: String,
     pub connection: deadpool_postgres::Config,
+    /// The maximum time to wait for a connection from the pool, of any target.
+    ///
+    /// Overrides the wait timeout of the pool configuration of the targets.
+    #[serde(default, with = "humantime_serde")]
+    pub pool_wait_timeout: Option<Duration>,
+    /// Additional targets, receiving the same rows as the primary one.
+    #[serde(default)]
+    pub secondaries: HashMap<String, TargetConfig>,
+    #[serde(default)]
+    pub fan_out: FanOut,
+    /// The number of events which may be queued for asynchronous replication.
+    #[serde(default = "default_replication_queue")]
+    pub replication_queue: usize,
+    #[serde(default)]
+    pub layout: Layout,
+    #[serde(default)]
+    pub narrow: NarrowConfig,
 }
 
 fn default_time_column() -> String {
     "time".to_string()
 }
 
-pub struct PostgresWriter {
+#[inline]
+fn default_replication_queue() -> usize {
+    1000
+}
+
+/// A secondary target.
+#[derive(Clone, Debug, Deserialize)]
+pub struct TargetConfig {
+    /// The table to write to, defaults to the table of the primary target.
+    pub table: Option<String>,
+    pub connection: deadpool_postgres::Config,
+}
+
+/// How writes are distributed to secondary targets.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum FanOut {
+    /// Write to all targets, all writes must succeed.
+    #[default]
+    All,
+    /// Write to all targets, only the write to the primary target must succeed.
+    BestEffort,
+    /// Only write to the primary target, and replicate to secondaries in the background.
+    Async,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Layout {
+    /// Write one row per event, with a column per field.
+    #[default]
+    Wide,
+    /// Write one row per field, with the name and value in dedicated columns.
+    Narrow,
+}
+
+/// The columns of the narrow layout.
+#[derive(Clone, Debug, Deserialize)]
+pub struct NarrowConfig {
+    #[serde(default = "default_name_column")]
+    pub name_column: String,
+    /// Column for floating point values, and integers, unless an integer column is configured.
+    #[serde(default = "default_double_column")]
+    pub double_column: String,
+    #[serde(default = "default_text_column")]
+    pub text_column: String,
+    #[serde(default = "default_bool_column")]
+    pub bool_column: String,
+    pub integer_column: Option<String>,
+}
+
+impl Default for NarrowConfig {
+    fn default() -> Self {
+        Self {
+            name_column: default_name_column(),
+            double_column: default_double_column(),
+            text_column: default_text_column(),
+            bool_column: default_bool_column(),
+            integer_column: None,
+        }
+    }
+}
+
+fn default_name_column() -> String {
+    "name".to_string()
+}
+
+fn default_double_column() -> String {
+    "value_double".to_string()
+}
+
+fn default_text_column() -> String {
+    "value_text".to_string()
+}
+
+fn default_bool_column() -> String {
+    "value_bool".to_string()
+}
+
+/// A single database target.
+struct Target {
+    name: String,
     pool: Pool,
     table: String,
+}
+
+/// Create the connection pool of a target.
+///
+/// The runtime can't be configured, but is required for applying the timeouts of the pool.
+fn create_pool(
+    mut connection: deadpool_postgres::Config,
+    wait_timeout: Option<Duration>,
+) -> anyhow::Result<Pool> {
+    let pool = connection.pool.get_or_insert_with(PoolConfig::default);
+    pool.runtime = Runtime::Tokio1;
+    if wait_timeout.is_some() {
+        pool.timeouts.wait = wait_timeout;
+    }
+    Ok(connection.create_pool(NoTls)?)
+}
+
+impl Target {
+    /// Write the insertions, in a single transaction, recording the outcome in the metrics.
+    async fn write(
+        &self,
+        pipeline: &str,
+        insertions: &[PostgresInsertion],
+        processed: Option<(&str, &EventKey)>,
+    ) -> Result<bool, ServiceError> {
+        let result = match self.connection().await {
+            Ok(mut connection) => self
+                .write_con(&mut connection, insertions, processed)
+                .await
+                .map_err(|err| ServiceError::Target(format!("{}: {}", self.name, err))),
+            Err(err) => Err(err),
+        };
+
+        let outcome = match &result {
+            Ok(_) => "success",
+            Err(_) => "failure",
+        };
+        metrics::TARGET_WRITES
+            .with_label_values(&[pipeline, &self.name, outcome])
+            .inc();
+
+        result
+    }
+
+    /// Get a connection from the pool, waiting no longer than the configured timeouts.
+    async fn connection(&self) -> Result<Client, ServiceError> {
+        self.pool.get().await.map_err(|err| match err {
+            PoolError::Timeout(_) => ServiceError::Unavailable(format!(
+                "{}: Timed out waiting for a database connection",
+                self.name
+            )),
+            err => ServiceError::Target(format!("{}: {}", self.name, err)),
+        })
+    }
+
+    async fn write_con(
+        &self,
+        connection: &mut Client,
+        insertions: &[PostgresInsertion],
+        processed: Option<(&str, &EventKey)>,
+    ) -> Result<bool, tokio_postgres::Error> {
+        // a single insertion, without recording it, doesn't need a transaction
+        if let ([insertion], None) = (insertions, processed) {
+            let sql = insertion.make_sql(&self.table);
+            let stmt = connection.prepare_typed(&sql, &insertion.types).await?;
+            connection.execute(&stmt, &insertion.params()).await?;
+            return Ok(true);
+        }
+
+        let tx = connection.transaction().await?;
+
+        if let Some((table, key)) = processed {
+            let marked = tx
+                .execute(
+                    format!(
+                        "INSERT INTO {} (source, id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+                        table
+                    )
+                    .as_str(),
+                    &[&key.source, &key.id],
+                )
+                .await?;
+            if marked == 0 {
+                // already processed, dropping the transaction will roll it back
+                return Ok(false);
+            }
+        }
+
+        for insertion in insertions {
+            let sql = insertion.make_sql(&self.table);
+            let stmt = tx.prepare_typed(&sql, &insertion.types).await?;
+            tx.execute(&stmt, &insertion.params()).await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(true)
+    }
+}
+
+type Batch = Arc<Vec<PostgresInsertion>>;
+
+/// The number of events for which the missing secondary targets are tracked.
+const PENDING_CAPACITY: usize = 10_000;
+
+/// Replicates writes to the secondary targets, in the background.
+struct Replication {
+    sender: Mutex<Option<mpsc::Sender<Batch>>>,
+    task: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Replication {
+    fn new(pipeline: String, targets: Vec<Arc<Target>>, capacity: usize) -> Self {
+        let (sender, mut receiver) = mpsc::channel::<Batch>(capacity);
+
+        let task = actix_web::rt::spawn(async move {
+            while let Some(insertions) = receiver.recv().await {
+                let writes = targets
+                    .iter()
+                    .map(|target| target.write(&pipeline, &insertions, None));
+                for result in futures::future::join_all(writes).await {
+                    if let Err(err) = result {
+                        log::warn!("Failed to replicate write: {}", err);
+                    }
+                }
+            }
+        });
+
+        Self {
+            sender: Mutex::new(Some(sender)),
+            task: Mutex::new(Some(task)),
+        }
+    }
+
+    /// Queue the insertions for replication, dropping them if the queue is full.
+    fn replicate(&self, pipeline: &str, insertions: Batch) {
+        let sender = self.sender.lock().unwrap();
+        let result = match sender.as_ref() {
+            Some(sender) => sender.try_send(insertions).is_ok(),
+            None => false,
+        };
+        if !result {
+            log::warn!("Replication queue is full or closed, dropping write");
+            metrics::TARGET_WRITES
+                .with_label_values(&[pipeline, "replication", "dropped"])
+                .inc();
+        }
+    }
+
+    /// Stop accepting writes, and wait for queued writes to be replicated, until the deadline.
+    async fn shutdown(&self, deadline: Instant) {
+        self.sender.lock().unwrap().take();
+        let task = self.task.lock().unwrap().take();
+
+        if let Some(mut task) = task {
+            let deadline = tokio::time::Instant::from_std(deadline);
+            if tokio::time::timeout_at(deadline, &mut task).await.is_err() {
+                log::warn!("Replication did not complete in time, abandoning queued writes");
+                task.abort();
+            }
+        }
+    }
+}
+
+pub struct PostgresWriter {
+    pipeline: String,
+    primary: Target,
+    secondaries: Vec<Arc<Target>>,
+    fan_out: FanOut,
+    replication: Option<Replication>,
+    /// The secondary targets which still miss an event, after the write partially failed.
+    pending: Mutex<LruCache<EventKey, Vec<String>>>,
     time_column: String,
+    layout: Layout,
+    narrow: NarrowConfig,
+    in_flight: InFlight,
 }
 
 impl PostgresWriter {
-    pub fn new(config: Config) -> anyhow::Result<PostgresWriter> {
-        Ok(Self {
-            pool: config.connection.create_pool(NoTls)?,
+    pub fn new(pipeline: &str, config: Config) -> anyhow::Result<PostgresWriter> {
+        let primary = Target {
+            name: "primary".into(),
+            pool: create_pool(config.connection, config.pool_wait_timeout)?,
             table: config.table,
+        };
+
+        let mut secondaries = Vec::with_capacity(config.secondaries.len());
+        for (name, target) in config.secondaries {
+            if name == primary.name {
+                anyhow::bail!("Secondary target must not be named '{}'", primary.name);
+            }
+            secondaries.push(Arc::new(Target {
+                name,
+                pool: create_pool(target.connection, config.pool_wait_timeout)?,
+                table: target.table.unwrap_or_else(|| primary.table.clone()),
+            }));
+        }
+
+        let replication = match (config.fan_out, secondaries.is_empty()) {
+            (FanOut::Async, false) => Some(Replication::new(
+                pipeline.to_string(),
+                secondaries.clone(),
+                config.replication_queue,
+            )),
+            _ => None,
+        };
+
+        Ok(Self {
+            pipeline: pipeline.to_string(),
+            primary,
+            secondaries,
+            fan_out: config.fan_out,
+            replication,
+            pending: Mutex::new(LruCache::new(PENDING_CAPACITY)),
             time_column: config.time_column,
+            layout: config.layout,
+            narrow: config.narrow,
+            in_flight: Default::default(),
         })
     }
 
+    /// Start counting writes, which are flushed or abandoned while shutting down.
+    pub fn start_draining(&self) {
+        self.in_flight.start_draining();
+    }
+
+    /// Stop accepting new writes.
+    pub fn close(&self) {
+        self.in_flight.close();
+    }
+
+    /// Wait for pending writes and replications to complete, until the deadline is reached, and
+    /// close the pools.
+    pub async fn shutdown(&self, deadline: Instant) {
+        self.in_flight.close();
+        let stats = self.in_flight.drain(deadline).await;
+        if let Some(replication) = &self.replication {
+            replication.shutdown(deadline).await;
+        }
+
+        self.primary.pool.close();
+        for target in &self.secondaries {
+            target.pool.close();
+        }
+
+        log::info!(
+            "Writer shut down - flushed: {}, abandoned: {}",
+            stats.flushed,
+            stats.abandoned
+        );
+    }
+
     pub async fn new_insertion(
         &self,
         timestamp: DateTime<Utc>,
     ) -> Result<PostgresInsertion, ServiceError> {
         let fields = vec![self.time_column.clone()];
         let types = vec![PgType::TIMESTAMPTZ];
-        let values: Vec<Box<dyn ToSql + Sync>> = vec![Box::new(timestamp)];
+        let values: Vec<Box<dyn ToSql + Send + Sync>> = vec![Box::new(timestamp)];
 
         Ok(PostgresInsertion {
             fields,
@@ -65,35 +401,227 @@ impl PostgresWriter {
         })
     }
 
-    pub async fn write(&self, insertion: PostgresInsertion) -> Result<(), ServiceError> {
-        self.write_con(insertion)
-            .await
-            .map_err(|err| ServiceError::Target(err.to_string()))
+    /// Create the insertions for the fields and tags of a row, based on the layout.
+    pub async fn new_insertions<'a>(
+        &self,
+        timestamp: DateTime<Utc>,
+        fields: Vec<(Cow<'a, str>, Type)>,
+        tags: Vec<(Cow<'a, str>, Type)>,
+    ) -> Result<Vec<PostgresInsertion>, ServiceError> {
+        match self.layout {
+            Layout::Wide => {
+                let mut insertion = self.new_insertion(timestamp).await?;
+                for (field, value) in fields {
+                    insertion = insertion.add_field(&field, value);
+                }
+                for (tag, value) in tags {
+                    insertion = insertion.add_tag(&tag, value);
+                }
+                Ok(vec![insertion])
+            }
+            Layout::Narrow => {
+                let mut insertions = Vec::with_capacity(fields.len());
+                for (field, value) in fields {
+                    let column = match &value {
+                        Type::Null => continue,
+                        Type::Boolean(_) => &self.narrow.bool_column,
+                        Type::String(_) => &self.narrow.text_column,
+                        Type::Float(_) => &self.narrow.double_column,
+                        Type::SignedInteger(_) | Type::UnsignedInteger(_) => {
+                            match &self.narrow.integer_column {
+                                Some(column) => column,
+                                None => &self.narrow.double_column,
+                            }
+                        }
+                    };
+                    let value = match (value, &self.narrow.integer_column) {
+                        (Type::SignedInteger(value), None) => Type::Float(value as f64),
+                        (Type::UnsignedInteger(value), None) => Type::Float(value as f64),
+                        (value, _) => value,
+                    };
+
+                    let mut insertion = self
+                        .new_insertion(timestamp)
+                        .await?
+                        .add_field(&self.narrow.name_column, Type::String(field.into_owned()))
+                        .add_field(column, value);
+                    for (tag, value) in &tags {
+                        insertion = insertion.add_tag(tag, value.clone());
+                    }
+                    insertions.push(insertion);
+                }
+                Ok(insertions)
+            }
+        }
     }
 
-    async fn write_con(
+    /// The name of the time column.
+    pub fn time_column(&self) -> &str {
+        &self.time_column
+    }
+
+    /// Record the secondary targets which still miss the event, as after a failed write.
+    #[cfg(test)]
+    pub fn set_missing(&self, key: EventKey, missing: Vec<String>) {
+        self.pending.lock().unwrap().put(key, missing);
+    }
+
+    /// Query the names of the columns of the primary target table.
+    pub async fn columns(&self) -> Result<HashSet<String>, ServiceError> {
+        let connection = self.primary.connection().await?;
+
+        let rows = match self.primary.table.split_once('.') {
+            Some((schema, table)) => {
+                connection
+                    .query(
+                        "SELECT column_name FROM information_schema.columns WHERE table_schema = $1 AND table_name = $2",
+                        &[&schema, &table],
+                    )
+                    .await
+            }
+            None => {
+                connection
+                    .query(
+                        "SELECT column_name FROM information_schema.columns WHERE table_schema = ANY(current_schemas(false)) AND table_name = $1",
+                        &[&self.primary.table],
+                    )
+                    .await
+            }
+        }
+        .map_err(|err| ServiceError::Target(err.to_string()))?;
+
+        Ok(rows.iter().map(|row| row.get(0)).collect())
+    }
+
+    /// Write the insertions, in a single transaction per target.
+    ///
+    /// If a table and key are provided, the event is recorded as processed in that table, as part
+    /// of the transaction on the primary target. Returns `false` if the event was already
+    /// recorded as processed before. In this case, nothing is being written.
+    ///
+    /// If a key is provided, and a previous write of the event failed on some secondary targets
+    /// only, just those targets are written to.
+    pub async fn write(
+        &self,
+        insertions: Vec<PostgresInsertion>,
+        key: Option<&EventKey>,
+        processed: Option<&str>,
+    ) -> Result<bool, ServiceError> {
+        let guard = self
+            .in_flight
+            .enter()
+            .ok_or_else(|| ServiceError::Unavailable("Shutting down".into()))?;
+
+        let result = self.write_targets(insertions, key, processed).await;
+
+        // a failed write was not flushed, and counts as abandoned
+        if result.is_ok() {
+            guard.complete();
+        }
+
+        result
+    }
+
+    async fn write_targets(
         &self,
-        insertion: PostgresInsertion,
-    ) -> Result<(), PoolError<tokio_postgres::Error>> {
-        let connection = self.pool.get().await?;
+        insertions: Vec<PostgresInsertion>,
+        key: Option<&EventKey>,
+        processed: Option<&str>,
+    ) -> Result<bool, ServiceError> {
+        let missing = key.and_then(|key| self.pending.lock().unwrap().pop(key));
+
+        let targets: Vec<&Target> = match missing {
+            Some(missing) => {
+                // the primary target, and some secondaries, have been written to before
+                log::debug!("Retrying write to secondary targets: {:?}", missing);
+                self.secondaries
+                    .iter()
+                    .filter(|target| missing.contains(&target.name))
+                    .map(AsRef::as_ref)
+                    .collect()
+            }
+            None => {
+                // the primary target decides if the event is a duplicate, so write it first
+                if !self
+                    .primary
+                    .write(&self.pipeline, &insertions, processed.zip(key))
+                    .await?
+                {
+                    return Ok(false);
+                }
+
+                if self.secondaries.is_empty() {
+                    return Ok(true);
+                }
+
+                if let Some(replication) = &self.replication {
+                    replication.replicate(&self.pipeline, Arc::new(insertions));
+                    return Ok(true);
+                }
+
+                self.secondaries.iter().map(AsRef::as_ref).collect()
+            }
+        };
 
-        let sql = insertion.make_sql(&self.table);
+        let writes = targets
+            .iter()
+            .map(|target| target.write(&self.pipeline, &insertions, None));
+        let results = futures::future::join_all(writes).await;
+
+        match fan_out(self.fan_out, &targets, results) {
+            Ok(()) => Ok(true),
+            Err((missing, err)) => {
+                if let Some(key) = key {
+                    self.pending.lock().unwrap().put(key.clone(), missing);
+                }
+                Err(err)
+            }
+        }
+    }
+}
 
-        let stmt = connection.prepare_typed(&sql, &insertion.types).await?;
-        let values: Vec<_> = insertion.values.iter().map(|v| v.as_ref()).collect();
-        connection.execute(&stmt, &values).await?;
+/// Evaluate the results of writing to the secondary targets, according to the fan out policy.
+///
+/// On failure, returns the names of the targets which failed, along with the first error.
+fn fan_out(
+    fan_out: FanOut,
+    targets: &[&Target],
+    results: Vec<Result<bool, ServiceError>>,
+) -> Result<(), (Vec<String>, ServiceError)> {
+    let mut missing = Vec::new();
+    let mut error = None;
+
+    for (target, result) in targets.iter().zip(results) {
+        match (result, fan_out) {
+            (Ok(_), _) => {}
+            (Err(err), FanOut::All) => {
+                missing.push(target.name.clone());
+                error.get_or_insert(err);
+            }
+            (Err(err), _) => log::warn!("Failed to write to secondary target: {}", err),
+        }
+    }
 
-        Ok(())
+    match error {
+        Some(err) => Err((missing, err)),
+        None => Ok(()),
     }
 }
 
 pub struct PostgresInsertion {
     fields: Vec<String>,
     types: Vec<PgType>,
-    values: Vec<Box<dyn ToSql + Sync>>,
+    values: Vec<Box<dyn ToSql + Send + Sync>>,
 }
 
 impl PostgresInsertion {
+    fn params(&self) -> Vec<&(dyn ToSql + Sync)> {
+        self.values
+            .iter()
+            .map(|v| v.as_ref() as &(dyn ToSql + Sync))
+            .collect()
+    }
+
     pub fn make_sql(&self, table: &str) -> String {
         let mut str = String::with_capacity(8 * 1024);
 
@@ -108,7 +636,7 @@ impl PostgresInsertion {
             } else {
                 str.push_str(", ");
             }
-            str.push_str(&field);
+            str.push_str(field);
         }
 
         str.push_str(") VALUES (");
@@ -120,12 +648,12 @@ impl PostgresInsertion {
             str.push_str(&format!("${}", i));
         }
 
-        str.push_str(")");
+        str.push(')');
 
         str
     }
 
-    fn add_param(mut self, field: String, param: (PgType, Box<dyn ToSql + Sync>)) -> Self {
+    fn add_param(mut self, field: String, param: (PgType, Box<dyn ToSql + Send + Sync>)) -> Self {
         self.fields.push(field);
         self.types.push(param.0);
         self.values.push(param.1);
@@ -133,17 +661,35 @@ impl PostgresInsertion {
         self
     }
 
-    fn split(value: Type) -> (PgType, Box<dyn ToSql + Sync>) {
+    fn split(value: Type) -> (PgType, Box<dyn ToSql + Send + Sync>) {
         match value {
             Type::Boolean(value) => (PgType::BOOL, Box::new(value)),
             Type::Float(value) => (PgType::FLOAT8, Box::new(value)),
             Type::UnsignedInteger(value) => (PgType::NUMERIC, Box::new(Decimal::from(value))),
             Type::SignedInteger(value) => (PgType::INT8, Box::new(value)),
             Type::String(value) => (PgType::VARCHAR, Box::new(value)),
+            // let the database infer the actual type
+            Type::Null => (PgType::UNKNOWN, Box::new(Null)),
         }
     }
 }
 
+/// An untyped `NULL` value.
+#[derive(Debug)]
+struct Null;
+
+impl ToSql for Null {
+    fn to_sql(&self, _: &PgType, _: &mut BytesMut) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        Ok(IsNull::Yes)
+    }
+
+    fn accepts(_: &PgType) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
+
 impl Insertion<'_> for PostgresInsertion {
     fn add_field(self, field: &str, value: Type) -> Self {
         self.add_param(field.into(), Self::split(value))
@@ -161,11 +707,247 @@ pub enum Type {
     SignedInteger(i64),
     UnsignedInteger(u64),
     String(String),
+    Null,
+}
+
+/// Turn a name, provided by the payload, into a column name.
+///
+/// As the name will be part of the SQL statement, only ASCII alphanumeric characters are kept,
+/// everything else is replaced with `_`.
+pub fn column_name(name: &str) -> String {
+    let mut result: String = name
+        .chars()
+        .map(|c| match c {
+            c if c.is_ascii_alphanumeric() => c.to_ascii_lowercase(),
+            _ => '_',
+        })
+        .collect();
+    if result.starts_with(|c: char| c.is_ascii_digit()) {
+        result.insert(0, '_');
+    }
+    result
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use serde_json::json;
+
+    fn writer(config: serde_json::Value) -> PostgresWriter {
+        let mut config: Config = serde_json::from_value(config).unwrap();
+        config.connection.host = Some("localhost".into());
+        config.connection.dbname = Some("test".into());
+        PostgresWriter::new("test", config).unwrap()
+    }
+
+    /// The columns and types of the insertions.
+    fn columns(insertions: &[PostgresInsertion]) -> Vec<Vec<(&str, PgType)>> {
+        insertions
+            .iter()
+            .map(|insertion| {
+                insertion
+                    .fields
+                    .iter()
+                    .map(String::as_str)
+                    .zip(insertion.types.iter().cloned())
+                    .collect()
+            })
+            .collect()
+    }
+
+    fn values() -> Vec<(Cow<'static, str>, Type)> {
+        vec![
+            (Cow::Borrowed("temp"), Type::Float(21.5)),
+            (Cow::Borrowed("count"), Type::SignedInteger(3)),
+            (Cow::Borrowed("label"), Type::String("kitchen".into())),
+            (Cow::Borrowed("missing"), Type::Null),
+        ]
+    }
+
+    #[actix_web::test]
+    async fn test_wide_layout() {
+        let writer = writer(json!({"table": "test", "connection": {}}));
+        let tags = vec![(Cow::Borrowed("device"), Type::String("d1".into()))];
+
+        let insertions = writer
+            .new_insertions(Utc::now(), values(), tags)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            columns(&insertions),
+            vec![vec![
+                ("time", PgType::TIMESTAMPTZ),
+                ("temp", PgType::FLOAT8),
+                ("count", PgType::INT8),
+                ("label", PgType::VARCHAR),
+                ("missing", PgType::UNKNOWN),
+                ("device", PgType::VARCHAR),
+            ]]
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_narrow_layout() {
+        let writer = writer(json!({"table": "test", "connection": {}, "layout": "narrow"}));
+        let tags = vec![(Cow::Borrowed("device"), Type::String("d1".into()))];
+
+        let insertions = writer
+            .new_insertions(Utc::now(), values(), tags)
+            .await
+            .unwrap();
+
+        // one row per value, skipping NULL, and integers stored as double by default
+        let row = |column, r#type| {
+            vec![
+                ("time", PgType::TIMESTAMPTZ),
+                ("name", PgType::VARCHAR),
+                (column, r#type),
+                ("device", PgType::VARCHAR),
+            ]
+        };
+        assert_eq!(
+            columns(&insertions),
+            vec![
+                row("value_double", PgType::FLOAT8),
+                row("value_double", PgType::FLOAT8),
+                row("value_text", PgType::VARCHAR),
+            ]
+        );
+        assert_eq!(format!("{:?}", insertions[1].values[1]), r#""count""#);
+        assert_eq!(format!("{:?}", insertions[1].values[2]), "3.0");
+    }
+
+    #[actix_web::test]
+    async fn test_narrow_layout_integer_column() {
+        let writer = writer(json!({
+            "table": "test",
+            "connection": {},
+            "layout": "narrow",
+            "narrow": {"name_column": "field", "integer_column": "value_int"},
+        }));
+
+        let insertions = writer
+            .new_insertions(Utc::now(), values(), vec![])
+            .await
+            .unwrap();
+
+        let columns: Vec<_> = columns(&insertions)
+            .into_iter()
+            .map(|columns| columns[1..].to_vec())
+            .collect();
+        assert_eq!(
+            columns,
+            vec![
+                vec![("field", PgType::VARCHAR), ("value_double", PgType::FLOAT8)],
+                vec![("field", PgType::VARCHAR), ("value_int", PgType::INT8)],
+                vec![("field", PgType::VARCHAR), ("value_text", PgType::VARCHAR)],
+            ]
+        );
+    }
+
+    /// A writer with two secondary targets, none of which can be connected to.
+    fn fan_out_writer(fan_out: &str) -> PostgresWriter {
+        let connection = json!({"host": "localhost", "port": 1, "dbname": "test"});
+        writer(json!({
+            "table": "test",
+            "connection": connection,
+            "fan_out": fan_out,
+            "secondaries": {
+                "a": {"connection": connection},
+                "b": {"connection": connection},
+            },
+        }))
+    }
+
+    fn targets(writer: &PostgresWriter) -> Vec<&Target> {
+        let mut targets: Vec<_> = writer.secondaries.iter().map(AsRef::as_ref).collect();
+        targets.sort_by(|a, b| a.name.cmp(&b.name));
+        targets
+    }
+
+    fn key(id: &str) -> EventKey {
+        EventKey {
+            source: "drogue".into(),
+            id: id.into(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_fan_out_all() {
+        let writer = fan_out_writer("all");
+        let targets = targets(&writer);
+
+        assert!(fan_out(FanOut::All, &targets, vec![Ok(true), Ok(true)]).is_ok());
+
+        let results = vec![Ok(true), Err(ServiceError::Target("b: failed".into()))];
+        match fan_out(FanOut::All, &targets, results) {
+            Err((missing, err)) => {
+                assert_eq!(missing, vec!["b".to_string()]);
+                assert_eq!(
+                    err.to_string(),
+                    ServiceError::Target("b: failed".into()).to_string()
+                );
+            }
+            Ok(()) => panic!("Expected the fan out to fail"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_fan_out_best_effort() {
+        let writer = fan_out_writer("best-effort");
+        let targets = targets(&writer);
+
+        let results = vec![
+            Err(ServiceError::Target("a: failed".into())),
+            Err(ServiceError::Target("b: failed".into())),
+        ];
+        assert!(fan_out(FanOut::BestEffort, &targets, results).is_ok());
+        assert!(writer.replication.is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_fan_out_async() {
+        assert!(fan_out_writer("async").replication.is_some());
+        assert!(fan_out_writer("all").replication.is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_retry_missing_targets() {
+        let writer = fan_out_writer("all");
+        let insertion = writer.new_insertion(Utc::now()).await.unwrap();
+        // a new event is written to the primary target first
+        let err = writer.write(vec![insertion], Some(&key("1")), None).await;
+        assert!(matches!(err, Err(ServiceError::Target(msg)) if msg.starts_with("primary:")));
+        assert!(writer.pending.lock().unwrap().is_empty());
+
+        // a retry only writes to the targets which failed before, and records them again
+        writer.set_missing(key("2"), vec!["b".to_string()]);
+        let insertion = writer.new_insertion(Utc::now()).await.unwrap();
+        let err = writer.write(vec![insertion], Some(&key("2")), None).await;
+        assert!(matches!(err, Err(ServiceError::Target(msg)) if msg.starts_with("b:")));
+        assert_eq!(
+            writer.pending.lock().unwrap().get(&key("2")),
+            Some(&vec!["b".to_string()])
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_pool_timeouts() {
+        let writer = writer(json!({
+            "table": "test",
+            "connection": {
+                "port": 1,
+                "pool": {"max_size": 1, "timeouts": {"create": {"secs": 1, "nanos": 0}}},
+            },
+        }));
+
+        // without a runtime, the pool would fail right away, instead of trying to connect
+        match writer.columns().await {
+            Err(ServiceError::Target(msg)) => assert!(!msg.contains("runtime"), "{}", msg),
+            _ => panic!("Expected the connection to fail"),
+        }
+    }
 
     #[test]
     fn test_sql() {